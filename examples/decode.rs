@@ -0,0 +1,88 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decoding benchmark, run with `cargo run --release --example decode`.
+//!
+//! The pickles are the same bytes as CPython writes for
+//!
+//!     records = [{'id': i, 'name': 'user%d' % i, 'score': i * 0.5,
+//!                 'active': i % 2 == 0, 'tags': ['a', 'b']}
+//!                for i in range(200000)]
+//!     strings = ['s%d' % i for i in range(1000000)]
+//!
+//! with `pickle.dumps(records, 2)`, `pickle.dumps(records, 4)` and
+//! `pickle.dumps(strings, 2)`.
+
+use std::io::{Cursor};
+use std::time::{Instant};
+
+extern crate pickle;
+
+use pickle::value::{Value, Text, Dict, Shared};
+use pickle::pickler::{Pickler};
+
+const RUNS: usize = 7;
+
+fn unicode(s: &'static str) -> Value<'static> {
+    Value::Unicode(Text::from(s))
+}
+
+fn records() -> Value<'static> {
+    // Shared like the interned strings of CPython, so that they are memoized
+    let keys = [unicode("id"), unicode("name"), unicode("score"), unicode("active"), unicode("tags")];
+    let (a, b) = (unicode("a"), unicode("b"));
+
+    let mut records = Vec::new();
+    for i in 0..200000 {
+        let mut record = Dict::new();
+        record.insert(keys[0].clone(), Value::Int(i));
+        record.insert(keys[1].clone(), Value::Unicode(Text::from(format!("user{}", i))));
+        record.insert(keys[2].clone(), Value::Float(i as f64 * 0.5));
+        record.insert(keys[3].clone(), Value::Bool(i % 2 == 0));
+        record.insert(keys[4].clone(), Value::List(Shared::new(vec![a.clone(), b.clone()])));
+        records.push(Value::Dict(Shared::new(record)));
+    }
+    Value::List(Shared::new(records))
+}
+
+fn strings() -> Value<'static> {
+    let strings = (0..1000000).map(|i| Value::Unicode(Text::from(format!("s{}", i)))).collect();
+    Value::List(Shared::new(strings))
+}
+
+fn dump(value: &Value, protocol: u8) -> Vec<u8> {
+    let mut pickler = Pickler::new(Vec::new(), protocol).unwrap();
+    pickler.dump(value).unwrap();
+    pickler.into_inner()
+}
+
+// Best time of a few runs, in milliseconds.
+fn best<F>(mut f: F) -> f64 where F: FnMut() {
+    (0..RUNS).map(|_| {
+        let start = Instant::now();
+        f();
+        let elapsed = start.elapsed();
+        elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1000000.0
+    }).fold(::std::f64::INFINITY, f64::min)
+}
+
+fn main() {
+    let records = records();
+    let strings = strings();
+    let pickles = [
+        ("records, protocol 2", dump(&records, 2)),
+        ("records, protocol 4", dump(&records, 4)),
+        ("strings, protocol 2", dump(&strings, 2)),
+    ];
+
+    for &(name, ref buffer) in &pickles {
+        let reader = best(|| { pickle::machine::unpickle(&mut Cursor::new(&buffer[..])).unwrap(); });
+        let slice = best(|| { pickle::machine::from_slice(&buffer[..]).unwrap(); });
+        println!("{}: {} bytes, reader {:.0} ms, slice {:.0} ms", name, buffer.len(), reader, slice);
+    }
+}
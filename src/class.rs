@@ -0,0 +1,46 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::rc::{Rc};
+
+use machine::{Error};
+use value::{Value, Construction};
+
+/// Custom constructor of instances of a Python class.
+pub trait Class {
+    /// Creates an instance from the arguments of REDUCE, NEWOBJ, NEWOBJ_EX,
+    /// INST or OBJ. `args` is a `Value::Tuple` and `kwargs`, if any, is a
+    /// `Value::Dict`.
+    fn construct<'a>(&self, construction: Construction, args: Value<'a>, kwargs: Option<Value<'a>>) -> Result<Value<'a>, Error>;
+
+    /// Applies the state passed by BUILD to an instance returned by
+    /// `construct`, like `__setstate__` does. Fails by default.
+    fn build<'a>(&self, instance: Value<'a>, state: Value<'a>) -> Result<Value<'a>, Error> {
+        let _ = (instance, state);
+        Err(Error::UnsupportedState)
+    }
+}
+
+impl<F> Class for F where F: for<'a> Fn(Construction, Value<'a>, Option<Value<'a>>) -> Result<Value<'a>, Error> {
+    fn construct<'a>(&self, construction: Construction, args: Value<'a>, kwargs: Option<Value<'a>>) -> Result<Value<'a>, Error> {
+        self(construction, args, kwargs)
+    }
+}
+
+/// Maps globals to classes, like overriding `Unpickler.find_class` does.
+pub trait ClassResolver {
+    /// Returns the class named `module.name`, or `None` to decode its
+    /// instances as `Value::Object`.
+    fn find_class(&self, module: &str, name: &str) -> Option<Rc<dyn Class>>;
+}
+
+impl<F> ClassResolver for F where F: Fn(&str, &str) -> Option<Rc<dyn Class>> {
+    fn find_class(&self, module: &str, name: &str) -> Option<Rc<dyn Class>> {
+        self(module, name)
+    }
+}
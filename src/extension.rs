@@ -0,0 +1,161 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::{HashMap};
+use std::str::{FromStr};
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum Error {
+        CodeOutOfRange(code: i32)
+        KeyAlreadyRegistered(module: String, name: String, code: i32)
+        CodeAlreadyInUse(code: i32, module: String, name: String)
+        NotRegistered(module: String, name: String, code: i32)
+        InvalidLine(line: usize)
+    }
+}
+
+/// Registry of extension codes used by EXT1, EXT2 and EXT4, the counterpart
+/// of `copyreg.add_extension` and friends.
+///
+/// It can also be parsed from a config with one `module name code` entry per
+/// line, blank lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionRegistry {
+    codes: HashMap<(String, String), i32>,
+    keys: HashMap<i32, (String, String)>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        ExtensionRegistry {
+            codes: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Registers `module.name` under `code`, which must be in `1..2**31`.
+    /// Registering the same pair again is allowed, reusing either the key
+    /// or the code for another pair is not.
+    pub fn add_extension(&mut self, module: &str, name: &str, code: i32) -> Result<(), Error> {
+        if code <= 0 {
+            return Err(Error::CodeOutOfRange(code))
+        }
+
+        let key = (module.to_string(), name.to_string());
+        match (self.codes.get(&key), self.keys.get(&code)) {
+            (Some(&c), Some(k)) if c == code && *k == key => return Ok(()),
+            (Some(&c), _) => return Err(Error::KeyAlreadyRegistered(key.0, key.1, c)),
+            (None, Some(k)) => return Err(Error::CodeAlreadyInUse(code, k.0.clone(), k.1.clone())),
+            (None, None) => (),
+        }
+
+        self.codes.insert(key.clone(), code);
+        self.keys.insert(code, key);
+        Ok(())
+    }
+
+    /// Unregisters `module.name`, which must be registered under `code`.
+    pub fn remove_extension(&mut self, module: &str, name: &str, code: i32) -> Result<(), Error> {
+        let key = (module.to_string(), name.to_string());
+        let registered = self.codes.get(&key) == Some(&code) && self.keys.get(&code) == Some(&key);
+        if !registered {
+            return Err(Error::NotRegistered(key.0, key.1, code))
+        }
+
+        self.codes.remove(&key);
+        self.keys.remove(&code);
+        Ok(())
+    }
+
+    /// Returns `(module, name)` registered under `code`.
+    pub fn get_extension(&self, code: i32) -> Option<(&str, &str)> {
+        self.keys.get(&code).map(|&(ref module, ref name)| (&module[..], &name[..]))
+    }
+
+    /// Returns the code of `module.name`.
+    pub fn get_code(&self, module: &str, name: &str) -> Option<i32> {
+        self.codes.get(&(module.to_string(), name.to_string())).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl FromStr for ExtensionRegistry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut registry = ExtensionRegistry::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(Error::InvalidLine(i + 1))
+            }
+            let code = match fields[2].parse() {
+                Ok(code) => code,
+                Err(_) => return Err(Error::InvalidLine(i + 1)),
+            };
+            try!(registry.add_extension(fields[0], fields[1], code));
+        }
+
+        Ok(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExtensionRegistry, Error};
+
+    #[test]
+    fn test_add_extension() {
+        let mut registry = ExtensionRegistry::new();
+        registry.add_extension("module", "name", 1).unwrap();
+        registry.add_extension("module", "name", 1).unwrap();
+        assert_eq!(registry.get_extension(1), Some(("module", "name")));
+        assert_eq!(registry.get_code("module", "name"), Some(1));
+
+        assert_eq!(registry.add_extension("module", "name", 2),
+                   Err(Error::KeyAlreadyRegistered("module".to_string(), "name".to_string(), 1)));
+        assert_eq!(registry.add_extension("module", "other", 1),
+                   Err(Error::CodeAlreadyInUse(1, "module".to_string(), "name".to_string())));
+        assert_eq!(registry.add_extension("module", "other", 0), Err(Error::CodeOutOfRange(0)));
+        assert_eq!(registry.add_extension("module", "other", -1), Err(Error::CodeOutOfRange(-1)));
+    }
+
+    #[test]
+    fn test_remove_extension() {
+        let mut registry = ExtensionRegistry::new();
+        registry.add_extension("module", "name", 1).unwrap();
+
+        assert_eq!(registry.remove_extension("module", "name", 2),
+                   Err(Error::NotRegistered("module".to_string(), "name".to_string(), 2)));
+        registry.remove_extension("module", "name", 1).unwrap();
+        assert_eq!(registry.get_extension(1), None);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_from_str() {
+        let registry: ExtensionRegistry = "# comment\n\nmodule name 1\n  other  name 2  \n".parse().unwrap();
+        assert_eq!(registry.get_extension(1), Some(("module", "name")));
+        assert_eq!(registry.get_extension(2), Some(("other", "name")));
+
+        assert_eq!("module name\n".parse::<ExtensionRegistry>().unwrap_err(), Error::InvalidLine(1));
+        assert_eq!("a b 1\nmodule name x\n".parse::<ExtensionRegistry>().unwrap_err(), Error::InvalidLine(2));
+        assert_eq!("a b 1\nc d 1\n".parse::<ExtensionRegistry>().unwrap_err(),
+                   Error::CodeAlreadyInUse(1, "a".to_string(), "b".to_string()));
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{Read, BufRead, Error as IoError, ErrorKind, Result as IoResult};
+use std::cmp::{min};
+use std::borrow::{Cow};
+
+/// Source of a pickle which may lend out its bytes for as long as `'a`.
+pub trait Input<'a>: Read + BufRead {
+    /// Reads exactly `length` bytes, borrowed from the input if it can.
+    fn read_bytes(&mut self, length: usize) -> IoResult<Cow<'a, [u8]>>;
+
+    /// Reads exactly `length` bytes like `read_bytes`, but passes them to
+    /// `borrowed` if they are borrowed from the input and to `copied`
+    /// otherwise, so that bytes copied anyway aren't put in a vector first.
+    fn read_bytes_with<T, B, C>(&mut self, length: usize, borrowed: B, copied: C) -> IoResult<T>
+        where B: FnOnce(&'a [u8]) -> T, C: FnOnce(&[u8]) -> T;
+
+    fn in_frame(&self) -> bool;
+
+    /// Starts a frame of the next `length` bytes.
+    fn load_frame(&mut self, length: usize) -> IoResult<()>;
+}
+
+// Frames are read in one go into a buffer reserved up to this size, larger
+// ones grow it as they are read. CPython writes frames of about 64 KiB.
+const FRAME_RESERVE: usize = 1 << 20;
+
+fn exhausted_frame() -> IoError {
+    IoError::new(ErrorKind::UnexpectedEof, "pickle exhausted before end of frame")
+}
+
+/// Contents of the current protocol 4 frame.
+pub struct Frame {
+    buf: Vec<u8>,
+    pos: usize,
+    // A line reached the end of the frame without ending
+    exhausted: bool,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            buf: Vec::new(),
+            pos: 0,
+            exhausted: false,
+        }
+    }
+
+    fn remaining(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+/// Reader which serves data from the current frame while there is any left
+/// and falls back to the underlying reader otherwise. Like in Python, reads
+/// started in a frame can't continue past its end.
+pub struct Framed<'a, R: 'a> {
+    frame: &'a mut Frame,
+    inner: &'a mut R,
+}
+
+impl<'a, R> Framed<'a, R> where R: Read + BufRead {
+    pub fn new(frame: &'a mut Frame, inner: &'a mut R) -> Self {
+        Framed {
+            frame: frame,
+            inner: inner,
+        }
+    }
+}
+
+impl<'a, 'b, R> Input<'b> for Framed<'a, R> where R: Read + BufRead {
+    fn read_bytes(&mut self, length: usize) -> IoResult<Cow<'b, [u8]>> {
+        // Like in `load_frame`, the length isn't trusted to preallocate
+        let mut buf = Vec::new();
+        try!(self.by_ref().take(length as u64).read_to_end(&mut buf));
+        if buf.len() != length {
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+        }
+        Ok(Cow::Owned(buf))
+    }
+
+    fn read_bytes_with<T, B, C>(&mut self, length: usize, _: B, copied: C) -> IoResult<T>
+        where B: FnOnce(&'b [u8]) -> T, C: FnOnce(&[u8]) -> T {
+        if self.in_frame() {
+            if length > self.frame.remaining().len() {
+                return Err(exhausted_frame())
+            }
+            let value = copied(&self.frame.remaining()[..length]);
+            self.frame.pos += length;
+            return Ok(value)
+        }
+
+        // Straight from the buffer of the underlying reader if it has them
+        {
+            let buf = try!(self.inner.fill_buf());
+            if buf.len() >= length {
+                let value = copied(&buf[..length]);
+                self.inner.consume(length);
+                return Ok(value)
+            }
+        }
+        let buf = try!(self.read_bytes(length));
+        Ok(copied(&buf))
+    }
+
+    fn in_frame(&self) -> bool {
+        !self.frame.is_empty()
+    }
+
+    /// Reads the whole next frame of `length` bytes from the underlying reader.
+    fn load_frame(&mut self, length: usize) -> IoResult<()> {
+        self.frame.buf.clear();
+        self.frame.pos = 0;
+        self.frame.exhausted = false;
+
+        // Don't trust the length to preallocate more than that, a truncated
+        // pickle shouldn't be able to request an arbitrary amount of memory.
+        self.frame.buf.reserve(min(length, FRAME_RESERVE));
+        try!(self.inner.by_ref().take(length as u64).read_to_end(&mut self.frame.buf));
+        if self.frame.buf.len() != length {
+            return Err(exhausted_frame())
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R> Read for Framed<'a, R> where R: Read + BufRead {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.frame.is_empty() {
+            return self.inner.read(buf)
+        }
+        if buf.len() > self.frame.remaining().len() {
+            return Err(exhausted_frame())
+        }
+
+        let n = buf.len();
+        buf.copy_from_slice(&self.frame.remaining()[..n]);
+        self.frame.pos += n;
+        Ok(n)
+    }
+}
+
+impl<'a, R> BufRead for Framed<'a, R> where R: Read + BufRead {
+    fn fill_buf(&mut self) -> IoResult<&[u8]> {
+        if self.frame.exhausted {
+            return Err(exhausted_frame())
+        }
+        if self.frame.is_empty() {
+            return self.inner.fill_buf()
+        }
+        Ok(self.frame.remaining())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.frame.is_empty() {
+            return self.inner.consume(amt)
+        }
+        self.frame.pos = min(self.frame.pos + amt, self.frame.buf.len());
+        // Only lines are read with `fill_buf`, this one would go on past
+        // the end of the frame
+        if self.frame.is_empty() && self.frame.buf.last() != Some(&b'\n') {
+            self.frame.exhausted = true;
+        }
+    }
+}
+
+/// Pickle in memory. Its bytes are lent out and frames need no copying,
+/// they only mark where they end.
+pub struct Slice<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    frame_end: usize,
+    // A line reached the end of the frame without ending
+    exhausted: bool,
+}
+
+impl<'a> Slice<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Slice {
+            buf: buf,
+            pos: 0,
+            frame_end: 0,
+            exhausted: false,
+        }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    // Fails if a read of `length` bytes would go on past the end of the
+    // frame it starts in.
+    fn check_frame(&self, length: usize) -> IoResult<()> {
+        if self.in_frame() && length > self.frame_end - self.pos {
+            return Err(exhausted_frame())
+        }
+        Ok(())
+    }
+
+    /// Number of bytes read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Position where the current or last frame ends.
+    pub fn frame_end(&self) -> usize {
+        self.frame_end
+    }
+}
+
+impl<'a> Input<'a> for Slice<'a> {
+    fn read_bytes(&mut self, length: usize) -> IoResult<Cow<'a, [u8]>> {
+        try!(self.check_frame(length));
+        let remaining = self.remaining();
+        if remaining.len() < length {
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+        }
+        self.pos += length;
+        Ok(Cow::Borrowed(&remaining[..length]))
+    }
+
+    fn read_bytes_with<T, B, C>(&mut self, length: usize, borrowed: B, copied: C) -> IoResult<T>
+        where B: FnOnce(&'a [u8]) -> T, C: FnOnce(&[u8]) -> T {
+        match try!(self.read_bytes(length)) {
+            Cow::Borrowed(bytes) => Ok(borrowed(bytes)),
+            Cow::Owned(bytes) => Ok(copied(&bytes)),
+        }
+    }
+
+    fn in_frame(&self) -> bool {
+        self.pos < self.frame_end
+    }
+
+    fn load_frame(&mut self, length: usize) -> IoResult<()> {
+        if self.remaining().len() < length {
+            return Err(exhausted_frame())
+        }
+        self.frame_end = self.pos + length;
+        self.exhausted = false;
+        Ok(())
+    }
+}
+
+impl<'a> Read for Slice<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        try!(self.check_frame(buf.len()));
+        let n = min(buf.len(), self.remaining().len());
+        buf[..n].copy_from_slice(&self.remaining()[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<'a> BufRead for Slice<'a> {
+    fn fill_buf(&mut self) -> IoResult<&[u8]> {
+        if self.exhausted {
+            return Err(exhausted_frame())
+        }
+        if self.in_frame() {
+            return Ok(&self.buf[self.pos..self.frame_end])
+        }
+        Ok(self.remaining())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let in_frame = self.in_frame();
+        self.pos = min(self.pos + amt, self.buf.len());
+        // Like in `Framed`, only lines are read with `fill_buf`
+        if in_frame && self.pos == self.frame_end && self.buf[self.pos - 1] != b'\n' {
+            self.exhausted = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, BufRead};
+    use std::borrow::{Cow};
+
+    use super::{Frame, Framed, Input, Slice, FRAME_RESERVE};
+
+    #[test]
+    fn test_load_frame() {
+        let mut frame = Frame::new();
+        let data = vec![b'N'; 100000];
+        let mut inner = Cursor::new(&data[..]);
+        {
+            let mut rd = Framed::new(&mut frame, &mut inner);
+            rd.load_frame(100000).unwrap();
+        }
+        assert_eq!(frame.buf.capacity(), 100000);
+
+        // A length with no data behind it reserves a bounded buffer
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b""[..]);
+        {
+            let mut rd = Framed::new(&mut frame, &mut inner);
+            assert!(rd.load_frame(usize::max_value()).is_err());
+        }
+        assert!(frame.buf.capacity() <= FRAME_RESERVE);
+    }
+
+    #[test]
+    fn test_framed() {
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abc\ndefgh"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+
+        rd.load_frame(5).unwrap();
+        assert!(rd.in_frame());
+
+        let mut line = Vec::new();
+        rd.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(line, b"abc\n");
+
+        let mut buf = [0; 1];
+        rd.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"d");
+        assert!(!rd.in_frame());
+
+        rd.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"e");
+
+        assert!(rd.load_frame(4).is_err());
+    }
+
+    #[test]
+    fn test_framed_straddle() {
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abcdef\n"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+        rd.load_frame(2).unwrap();
+        let mut buf = [0; 3];
+        assert!(rd.read_exact(&mut buf).is_err());
+
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abcdef\n"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+        rd.load_frame(2).unwrap();
+        assert!(rd.read_bytes(3).is_err());
+
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abcdef\n"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+        rd.load_frame(2).unwrap();
+        let mut line = Vec::new();
+        assert!(rd.read_until(b'\n', &mut line).is_err());
+    }
+
+    #[test]
+    fn test_read_bytes() {
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abc"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+
+        // Copied out of the reader
+        match rd.read_bytes(2).unwrap() {
+            Cow::Owned(bytes) => assert_eq!(bytes, b"ab"),
+            Cow::Borrowed(_) => assert!(false),
+        }
+        // Fails without allocating the length first
+        assert!(rd.read_bytes(usize::max_value()).is_err());
+
+        // Borrowed from the slice
+        let mut rd = Slice::new(&b"abc"[..]);
+        match rd.read_bytes(2).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, b"ab"),
+            Cow::Owned(_) => assert!(false),
+        }
+        assert!(rd.read_bytes(usize::max_value()).is_err());
+    }
+
+    #[test]
+    fn test_read_bytes_with() {
+        let mut frame = Frame::new();
+        let mut inner = Cursor::new(&b"abcdef"[..]);
+        let mut rd = Framed::new(&mut frame, &mut inner);
+        let copied = |s: &[u8]| s.to_vec();
+
+        rd.load_frame(3).unwrap();
+        assert_eq!(rd.read_bytes_with(2, |_| vec![], copied).unwrap(), b"ab");
+        assert!(rd.read_bytes_with(2, |_| vec![], copied).is_err());
+        rd.consume(1);
+        assert_eq!(rd.read_bytes_with(2, |_| vec![], copied).unwrap(), b"de");
+        assert!(rd.read_bytes_with(2, |_| vec![], copied).is_err());
+
+        let mut rd = Slice::new(&b"abc"[..]);
+        assert_eq!(rd.read_bytes_with(2, |s| s.to_vec(), |_| vec![]).unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_slice() {
+        let buf = b"abc\ndefgh";
+        let mut rd = Slice::new(&buf[..]);
+
+        rd.load_frame(5).unwrap();
+        assert!(rd.in_frame());
+
+        let mut line = Vec::new();
+        rd.read_until(b'\n', &mut line).unwrap();
+        assert_eq!(line, b"abc\n");
+
+        assert!(rd.read_bytes(2).is_err());
+        match rd.read_bytes(1).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, b"d"),
+            Cow::Owned(_) => assert!(false),
+        }
+        assert!(!rd.in_frame());
+        match rd.read_bytes(2).unwrap() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, b"ef"),
+            Cow::Owned(_) => assert!(false),
+        }
+
+        assert!(rd.load_frame(3).is_err());
+        assert!(rd.read_bytes(3).is_err());
+
+        let mut rd = Slice::new(&buf[..]);
+        rd.load_frame(2).unwrap();
+        let mut line = Vec::new();
+        assert!(rd.read_until(b'\n', &mut line).is_err());
+    }
+}
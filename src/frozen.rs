@@ -0,0 +1,152 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+use std::sync::{Arc};
+
+use graph::{ValueGraph, Node, NodeId};
+use value::{Value};
+
+/// Immutable value which can be shared between threads.
+///
+/// It's a node of a `ValueGraph` behind an `Arc`, so clones are cheap and
+/// shared references and cycles of the frozen value are kept: containers
+/// which were the same `Rc` are the same node.
+#[derive(Clone)]
+pub struct FrozenValue {
+    graph: Arc<ValueGraph>,
+    id: NodeId,
+}
+
+impl FrozenValue {
+    /// Freezes `value`, see `ValueGraph::from_value`.
+    pub fn new(value: Value) -> Self {
+        FrozenValue::from(ValueGraph::from_value(value))
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.graph[self.id]
+    }
+
+    pub fn graph(&self) -> &ValueGraph {
+        &self.graph
+    }
+
+    /// Returns another node of the same graph.
+    pub fn get(&self, id: NodeId) -> Option<FrozenValue> {
+        self.graph.get(id).map(|_| self.at(id))
+    }
+
+    /// Items of a list, tuple, set or frozenset; nothing for other values.
+    pub fn items(&self) -> Vec<FrozenValue> {
+        match *self.node() {
+            Node::List(ref items) | Node::Tuple(ref items) | Node::Set(ref items) | Node::FrozenSet(ref items) => {
+                items.iter().map(|&id| self.at(id)).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// Keys and values of a dict; nothing for other values.
+    pub fn entries(&self) -> Vec<(FrozenValue, FrozenValue)> {
+        match *self.node() {
+            Node::Dict(ref items) => items.iter().map(|&(k, v)| (self.at(k), self.at(v))).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether both are the same node of the same graph, like Python's `is`.
+    pub fn ptr_eq(this: &FrozenValue, other: &FrozenValue) -> bool {
+        Arc::ptr_eq(&this.graph, &other.graph) && this.id == other.id
+    }
+
+    fn at(&self, id: NodeId) -> FrozenValue {
+        FrozenValue {
+            graph: self.graph.clone(),
+            id: id,
+        }
+    }
+}
+
+impl From<ValueGraph> for FrozenValue {
+    fn from(graph: ValueGraph) -> Self {
+        let id = graph.root();
+        FrozenValue {
+            graph: Arc::new(graph),
+            id: id,
+        }
+    }
+}
+
+impl<'a> From<Value<'a>> for FrozenValue {
+    fn from(value: Value<'a>) -> Self {
+        FrozenValue::new(value)
+    }
+}
+
+impl fmt::Debug for FrozenValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrozenValue").field("id", &self.id).field("node", self.node()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor};
+    use std::thread;
+
+    use machine::{unpickle};
+    use graph::{Node};
+
+    use super::{FrozenValue};
+
+    fn freeze(buffer: &[u8]) -> FrozenValue {
+        FrozenValue::new(unpickle(&mut Cursor::new(buffer)).unwrap())
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn check<T: Send + Sync>() {}
+        check::<FrozenValue>();
+    }
+
+    #[test]
+    fn test_sharing() {
+        // l = [1]; [l, l, (l,)]
+        let value = freeze(b"\x80\x02]q\x00(]q\x01K\x01ah\x01h\x01\x85q\x02e.");
+        let items = value.items();
+        assert_eq!(items.len(), 3);
+        assert!(FrozenValue::ptr_eq(&items[0], &items[1]));
+        assert!(FrozenValue::ptr_eq(&items[0], &items[2].items()[0]));
+        assert_eq!(*items[0].items()[0].node(), Node::Int(1));
+
+        // l = []; l.append(l); d = {}; d['d'] = d; l.append(d)
+        let value = freeze(b"\x80\x02]q\x00(h\x00}q\x01X\x01\x00\x00\x00dq\x02h\x01se.");
+        let items = value.items();
+        assert!(FrozenValue::ptr_eq(&value, &items[0]));
+        let entries = items[1].entries();
+        assert_eq!(*entries[0].0.node(), Node::Unicode("d".to_string()));
+        assert!(FrozenValue::ptr_eq(&items[1], &entries[0].1));
+    }
+
+    #[test]
+    fn test_threads() {
+        let value = freeze(b"\x80\x02]q\x00(K\x01K\x02K\x03e.");
+        let threads: Vec<_> = (0..3).map(|i| {
+            let value = value.clone();
+            thread::spawn(move || value.items()[i].node().clone())
+        }).collect();
+
+        let nodes: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(nodes, vec![Node::Int(1), Node::Int(2), Node::Int(3)]);
+    }
+}
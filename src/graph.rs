@@ -0,0 +1,392 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Values owned by a single arena, in which containers refer to each other
+//! by node ids instead of `Rc`s, so that cycles are freed with the graph.
+
+use std::collections::{HashMap};
+use std::ops::{Index};
+use std::rc::{Rc};
+
+use num::bigint::{BigInt};
+
+use value::{self, Value, Shared, Contents, Construction, Dict, Set};
+
+pub type NodeId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    None,
+    Bool(bool),
+    Int(isize),
+    Long(BigInt),
+    Float(f64),
+    String(Vec<u8>),
+    Unicode(String),
+    Bytes(Vec<u8>),
+    ByteArray(Vec<u8>),
+    List(Vec<NodeId>),
+    Tuple(Vec<NodeId>),
+    Dict(Vec<(NodeId, NodeId)>),
+    Set(Vec<NodeId>),
+    FrozenSet(Vec<NodeId>),
+    Global {
+        module: String,
+        name: String,
+    },
+    Object(Object),
+}
+
+/// `value::Object` with its values replaced by node ids.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub construction: Construction,
+    pub class: NodeId,
+    pub args: NodeId,
+    pub kwargs: Option<NodeId>,
+    pub state: Option<NodeId>,
+    pub list_items: Vec<NodeId>,
+    pub dict_items: Vec<(NodeId, NodeId)>,
+}
+
+impl Node {
+    /// Ids of the nodes the node refers to.
+    pub fn children(&self) -> Vec<NodeId> {
+        match *self {
+            Node::List(ref items) | Node::Tuple(ref items) | Node::Set(ref items) | Node::FrozenSet(ref items) => {
+                items.clone()
+            },
+            Node::Dict(ref items) => items.iter().flat_map(|&(k, v)| vec![k, v]).collect(),
+            Node::Object(ref obj) => {
+                let mut children = vec![obj.class, obj.args];
+                children.extend(obj.kwargs);
+                children.extend(obj.state);
+                children.extend(obj.list_items.iter().cloned());
+                children.extend(obj.dict_items.iter().flat_map(|&(k, v)| vec![k, v]));
+                children
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Graph of the values reachable from a root value.
+///
+/// Shared containers are a single node referred to by several nodes, so both
+/// sharing and cycles are kept, but the whole graph is freed when it's
+/// dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueGraph {
+    nodes: Vec<Node>,
+    root: NodeId,
+}
+
+impl ValueGraph {
+    /// Converts `value` into a graph.
+    ///
+    /// Containers of `value` which are referenced only from `value` itself,
+    /// e.g. cycles created by the memo, are emptied during the conversion so
+    /// that `Rc` cycles don't keep them alive after it. Containers which are
+    /// also referenced from outside of `value` are left intact.
+    pub fn from_value(value: Value) -> Self {
+        let mut builder = Builder {
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            containers: Vec::new(),
+            pending: Vec::new(),
+        };
+
+        let root = builder.add(&value);
+        while let Some(i) = builder.pending.pop() {
+            let node = builder.fill(i);
+            let id = builder.containers[i].id;
+            builder.nodes[id] = node;
+        }
+        drop(value);
+        // The root is the first container, and the handle counted for it is
+        // gone now
+        if let Some(container) = builder.containers.first_mut() {
+            container.references -= 1;
+        }
+
+        builder.reclaim();
+        ValueGraph {
+            nodes: builder.nodes,
+            root: root,
+        }
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Index<NodeId> for ValueGraph {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+}
+
+impl<'a> From<Value<'a>> for ValueGraph {
+    fn from(value: Value<'a>) -> Self {
+        ValueGraph::from_value(value)
+    }
+}
+
+#[derive(Clone)]
+enum Rced<'a> {
+    Items(Shared<'a, Vec<Value<'a>>>, bool),
+    Dict(Shared<'a, Dict<'a>>),
+    Set(Shared<'a, Set<'a>>, bool),
+    Object(Shared<'a, value::Object<'a>>),
+}
+
+impl<'a> Rced<'a> {
+    fn strong_count(&self) -> usize {
+        match *self {
+            Rced::Items(ref rc, _) => Rc::strong_count(rc),
+            Rced::Dict(ref rc) => Rc::strong_count(rc),
+            Rced::Set(ref rc, _) => Rc::strong_count(rc),
+            Rced::Object(ref rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // Drops the contents, and with them the references they hold.
+    fn clear(&self) {
+        let mut values = Vec::new();
+        match *self {
+            Rced::Items(ref rc, _) => rc.borrow_mut().take_values(&mut values),
+            Rced::Dict(ref rc) => rc.borrow_mut().take_values(&mut values),
+            Rced::Set(ref rc, _) => rc.borrow_mut().take_values(&mut values),
+            Rced::Object(ref rc) => rc.borrow_mut().take_values(&mut values),
+        }
+    }
+}
+
+struct Container<'a> {
+    rc: Rced<'a>,
+    id: NodeId,
+    // References from within the converted value
+    references: usize,
+}
+
+struct Builder<'a> {
+    nodes: Vec<Node>,
+    ids: HashMap<usize, usize>,
+    containers: Vec<Container<'a>>,
+    // Containers whose nodes are yet to be filled
+    pending: Vec<usize>,
+}
+
+impl<'a> Builder<'a> {
+    // Adds a node for a value, a container gets a placeholder which is
+    // filled later, so that deep values don't recurse.
+    fn add(&mut self, value: &Value<'a>) -> NodeId {
+        let (ptr, rc) = match *value {
+            Value::None => return self.push(Node::None),
+            Value::Bool(b) => return self.push(Node::Bool(b)),
+            Value::Int(i) => return self.push(Node::Int(i)),
+            Value::Long(ref l) => return self.push(Node::Long(l.clone())),
+            Value::Float(f) => return self.push(Node::Float(f)),
+            Value::String(ref s) => return self.push(Node::String(s.to_vec())),
+            Value::Unicode(ref s) => return self.push(Node::Unicode(s.to_string())),
+            Value::Bytes(ref s) => return self.push(Node::Bytes(s.to_vec())),
+            Value::ByteArray(ref s) => return self.push(Node::ByteArray(s.clone())),
+            Value::Global { ref module, ref name } => {
+                return self.push(Node::Global { module: module.clone(), name: name.clone() })
+            },
+            Value::List(ref rc) => (rc.as_ptr() as usize, Rced::Items(rc.clone(), false)),
+            Value::Tuple(ref rc) => (rc.as_ptr() as usize, Rced::Items(rc.clone(), true)),
+            Value::Dict(ref rc) => (rc.as_ptr() as usize, Rced::Dict(rc.clone())),
+            Value::Set(ref rc) => (rc.as_ptr() as usize, Rced::Set(rc.clone(), false)),
+            Value::FrozenSet(ref rc) => (rc.as_ptr() as usize, Rced::Set(rc.clone(), true)),
+            Value::Object(ref rc) => (rc.as_ptr() as usize, Rced::Object(rc.clone())),
+        };
+
+        if let Some(&i) = self.ids.get(&ptr) {
+            self.containers[i].references += 1;
+            return self.containers[i].id
+        }
+
+        let id = self.push(Node::None);
+        self.ids.insert(ptr, self.containers.len());
+        self.pending.push(self.containers.len());
+        self.containers.push(Container {
+            rc: rc,
+            id: id,
+            references: 1,
+        });
+        id
+    }
+
+    fn push(&mut self, node: Node) -> NodeId {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn add_all<'b, I>(&mut self, values: I) -> Vec<NodeId> where I: Iterator<Item=&'b Value<'a>>, 'a: 'b {
+        values.map(|value| self.add(value)).collect()
+    }
+
+    fn add_pairs<'b, I>(&mut self, pairs: I) -> Vec<(NodeId, NodeId)> where I: Iterator<Item=&'b (Value<'a>, Value<'a>)>, 'a: 'b {
+        pairs.map(|&(ref k, ref v)| (self.add(k), self.add(v))).collect()
+    }
+
+    fn fill(&mut self, i: usize) -> Node {
+        match self.containers[i].rc.clone() {
+            Rced::Items(rc, false) => Node::List(self.add_all(rc.borrow().iter())),
+            Rced::Items(rc, true) => Node::Tuple(self.add_all(rc.borrow().iter())),
+            Rced::Dict(rc) => Node::Dict(self.add_pairs(rc.borrow().iter())),
+            Rced::Set(rc, false) => Node::Set(self.add_all(rc.borrow().iter())),
+            Rced::Set(rc, true) => Node::FrozenSet(self.add_all(rc.borrow().iter())),
+            Rced::Object(rc) => {
+                let obj = rc.borrow();
+                Node::Object(Object {
+                    construction: obj.construction,
+                    class: self.add(&obj.class),
+                    args: self.add(&obj.args),
+                    kwargs: obj.kwargs.as_ref().map(|kwargs| self.add(kwargs)),
+                    state: obj.state.as_ref().map(|state| self.add(state)),
+                    list_items: self.add_all(obj.list_items.iter()),
+                    dict_items: self.add_pairs(obj.dict_items.iter()),
+                })
+            },
+        }
+    }
+
+    // Like the cycle detection of CPython's garbage collector: containers
+    // with more strong references than the converted value accounts for are
+    // used elsewhere, they and everything reachable from them are kept, the
+    // rest are garbage once the value is dropped.
+    fn reclaim(&mut self) {
+        let mut external = Vec::new();
+        for container in &self.containers {
+            // One reference is held by the builder itself
+            if container.rc.strong_count() > container.references + 1 {
+                external.push(container.id);
+            }
+        }
+
+        let mut kept = vec![false; self.nodes.len()];
+        while let Some(id) = external.pop() {
+            if kept[id] {
+                continue
+            }
+            kept[id] = true;
+            external.extend(self.nodes[id].children());
+        }
+
+        for container in &self.containers {
+            if !kept[container.id] {
+                container.rc.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor};
+    use std::rc::{Rc};
+
+    use machine::{unpickle};
+    use value::{Value, Shared};
+
+    use super::{ValueGraph, Node};
+
+    #[test]
+    fn test_from_value() {
+        // l = [1]; [l, l, (l,)]
+        let value = unpickle(&mut Cursor::new(&b"\x80\x02]q\x00(]q\x01K\x01ah\x01h\x01\x85q\x02e."[..])).unwrap();
+        let graph = ValueGraph::from_value(value);
+        let (l, t) = match graph[graph.root()] {
+            Node::List(ref items) => {
+                assert_eq!(items.len(), 3);
+                assert_eq!(items[0], items[1]);
+                (items[0], items[2])
+            },
+            _ => panic!(),
+        };
+        match graph[l] {
+            Node::List(ref items) => assert_eq!(graph[items[0]], Node::Int(1)),
+            _ => panic!(),
+        }
+        assert_eq!(graph[t], Node::Tuple(vec![l]));
+    }
+
+    #[test]
+    fn test_cycles() {
+        // l = []; l.append(l); d = {}; d['d'] = d; l.append(d)
+        let value = unpickle(&mut Cursor::new(&b"\x80\x02]q\x00(h\x00}q\x01X\x01\x00\x00\x00dq\x02h\x01se."[..])).unwrap();
+        let weak = match value {
+            Value::List(ref l) => Rc::downgrade(l),
+            _ => panic!(),
+        };
+
+        let graph = ValueGraph::from_value(value);
+        assert!(weak.upgrade().is_none());
+
+        let root = graph.root();
+        let d = match graph[root] {
+            Node::List(ref items) => {
+                assert_eq!(items[0], root);
+                items[1]
+            },
+            _ => panic!(),
+        };
+        match graph[d] {
+            Node::Dict(ref items) => assert_eq!(items[0].1, d),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_external_references() {
+        let inner = Shared::new(vec![]);
+        inner.borrow_mut().push(Value::List(inner.clone()));
+        let outer = Shared::new(vec![Value::List(inner.clone())]);
+        outer.borrow_mut().push(Value::List(outer.clone()));
+        let weak = Rc::downgrade(&outer);
+
+        let graph = ValueGraph::from_value(Value::List(outer));
+        assert_eq!(graph.len(), 2);
+        assert!(weak.upgrade().is_none());
+        // Still referenced from here
+        assert_eq!(inner.borrow().len(), 1);
+        inner.borrow_mut().clear();
+    }
+
+    #[test]
+    fn test_clone() {
+        // l = [1]; l.append(l)
+        let value = unpickle(&mut Cursor::new(&b"\x80\x02]q\x00(K\x01h\x00e."[..])).unwrap();
+        let graph = ValueGraph::from_value(value.clone());
+        assert_eq!(graph.len(), 2);
+        match value {
+            Value::List(ref l) => {
+                assert_eq!(l.borrow().len(), 2);
+                l.borrow_mut().clear();
+            },
+            _ => panic!(),
+        }
+    }
+}
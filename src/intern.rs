@@ -0,0 +1,138 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::{RefCell};
+use std::collections::{HashSet};
+use std::hash::{Hash};
+use std::rc::{Rc};
+use std::mem::{size_of_val};
+
+use value::{Value, Bytes, Text};
+
+/// Counters of an `Interner`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InternStats {
+    /// Strings looked up.
+    pub strings: usize,
+    /// Strings replaced by an equal one seen before.
+    pub duplicates: usize,
+    /// Total length of the replaced strings.
+    pub bytes_saved: usize,
+}
+
+#[derive(Default)]
+struct Strings {
+    bytes: HashSet<Rc<[u8]>>,
+    texts: HashSet<Rc<str>>,
+    stats: InternStats,
+}
+
+/// Deduplicates the `String` and `Unicode` values decoded by a `Machine`,
+/// so that equal strings share one allocation even when the pickle didn't
+/// memoize them.
+///
+/// Clones share the strings seen so far, so one interner may serve several
+/// machines, and a clone kept aside reports the stats after `Machine::load`.
+/// Strings are kept until every clone is dropped. Strings borrowed from the
+/// input by `from_slice` take no memory and are left alone.
+#[derive(Clone, Default)]
+pub struct Interner(Rc<RefCell<Strings>>);
+
+fn intern<T: ?Sized + Hash + Eq>(set: &mut HashSet<Rc<T>>, stats: &mut InternStats, s: Rc<T>) -> Rc<T> {
+    stats.strings += 1;
+    if let Some(seen) = set.get(&*s) {
+        if !Rc::ptr_eq(seen, &s) {
+            stats.duplicates += 1;
+            stats.bytes_saved += size_of_val(&*s);
+        }
+        return seen.clone()
+    }
+    set.insert(s.clone());
+    s
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner::default()
+    }
+
+    pub fn stats(&self) -> InternStats {
+        self.0.borrow().stats
+    }
+
+    /// Number of distinct strings kept.
+    pub fn len(&self) -> usize {
+        let strings = self.0.borrow();
+        strings.bytes.len() + strings.texts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replaces the payload of a `String` or `Unicode` value by an equal
+    /// one seen before, other values are returned as they are.
+    pub fn intern<'a>(&self, value: Value<'a>) -> Value<'a> {
+        let mut strings = self.0.borrow_mut();
+        let strings = &mut *strings;
+        match value {
+            Value::String(Bytes::Shared(s)) => {
+                Value::String(Bytes::Shared(intern(&mut strings.bytes, &mut strings.stats, s)))
+            },
+            Value::Unicode(Text::Shared(s)) => {
+                Value::Unicode(Text::Shared(intern(&mut strings.texts, &mut strings.stats, s)))
+            },
+            value => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor};
+    use std::rc::{Rc};
+
+    use machine::{Machine};
+    use value::{Value, Text};
+
+    use super::{Interner, InternStats};
+
+    fn key(value: &Value) -> Rc<str> {
+        match *value {
+            Value::Dict(ref d) => match d.borrow().iter().next() {
+                Some(&(Value::Unicode(Text::Shared(ref s)), _)) => s.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_interner() {
+        // Pickled in fast mode: [{'name': 1}, {'name': 2}, 'ab']
+        let buffer = b"\x80\x02](}X\x04\x00\x00\x00nameK\x01s}X\x04\x00\x00\x00nameK\x02sX\x02\x00\x00\x00abe.";
+        let interner = Interner::new();
+        let mut machine = Machine::new();
+        machine.set_interner(interner.clone());
+
+        match machine.load(&mut Cursor::new(&buffer[..])) {
+            Ok(Value::List(ref items)) => {
+                let items = items.borrow();
+                assert!(Rc::ptr_eq(&key(&items[0]), &key(&items[1])));
+            },
+            _ => assert!(false),
+        }
+        assert_eq!(interner.stats(), InternStats { strings: 3, duplicates: 1, bytes_saved: 4 });
+        assert_eq!(interner.len(), 2);
+
+        let value = interner.intern(Value::Unicode("name".to_string().into()));
+        assert_eq!(interner.stats().duplicates, 2);
+        assert_eq!(value, Value::Unicode("name".into()));
+        assert_eq!(interner.intern(Value::Int(1)), Value::Int(1));
+    }
+}
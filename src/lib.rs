@@ -16,6 +16,18 @@ extern crate from_ascii;
 extern crate unicode_names;
 
 pub mod opcodes;
+pub mod opcode;
 pub mod value;
 pub mod machine;
+pub mod class;
+pub mod persistent;
+pub mod extension;
+pub mod graph;
+pub mod frozen;
+pub mod intern;
+pub mod pickler;
+pub mod recording;
 mod string;
+mod repr;
+mod frame;
+mod memo;
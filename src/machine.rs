@@ -8,17 +8,26 @@
 
 use std::io::{Read, BufRead, Error as IoError};
 use std::string::{FromUtf8Error};
-use std::collections::{HashMap};
-use std::cell::{RefCell};
+use std::str::{self, Utf8Error};
+use std::collections::{HashMap, VecDeque};
 use std::rc::{Rc};
+use std::mem::{replace};
 
-use num::{Zero};
+use num::{Zero, ToPrimitive};
 use num::bigint::{BigInt, ToBigInt, Sign};
-use byteorder::{ReadBytesExt, LittleEndian, BigEndian, Error as ByteorderError};
+use byteorder::{ByteOrder, ReadBytesExt, LittleEndian, BigEndian, Error as ByteorderError};
 use from_ascii::{FromAscii, ParseIntError, ParseFloatError};
 
 use string::{unescape, Error as UnescapeError};
-use value::{Value};
+use frame::{Input, Frame, Framed, Slice};
+use class::{Class, ClassResolver};
+use persistent::{PersistentLoader};
+use extension::{ExtensionRegistry};
+use value::{Value, Bytes, Text, Shared, Object, Construction, Set, Dict};
+use graph::{ValueGraph};
+use memo::{Memo};
+use intern::{Interner};
+use recording::{Recording};
 
 use opcodes::*;
 
@@ -31,6 +40,13 @@ quick_error! {
         InvalidValueOnStack
         InvalidGetValue
         InvalidPutValue
+        MissingBuffers
+        NotEnoughBuffers
+        UnsupportedState
+        UnhashableValue
+        UnsupportedPersistentId
+        InvalidExtensionCode(code: i32)
+        UnregisteredExtension(code: i32)
 
         Read(err: ByteorderError) {
             from()
@@ -51,6 +67,7 @@ quick_error! {
         InvalidString
         UnicodeError {
             from(FromUtf8Error)
+            from(Utf8Error)
         }
         UnescapeError(err: UnescapeError) {
             from()
@@ -58,6 +75,8 @@ quick_error! {
 
         InvalidProto(proto: u8)
         NegativeLength {}
+        LengthTooLarge {}
+        UnfinishedFrame {}
 
         #[doc(hidden)]
         __Nonexhaustive
@@ -71,7 +90,7 @@ pub enum BooleanOrInt {
 }
 
 macro_rules! rc {
-    ($term: expr) => (Rc::new(RefCell::new($term)))
+    ($term: expr) => (Shared::new($term))
 }
 
 fn read_until_newline<R>(rd: &mut R) -> Result<Vec<u8>, Error> where R: Read + BufRead {
@@ -95,7 +114,24 @@ fn read_decimal_int<R>(rd: &mut R) -> Result<BooleanOrInt, Error> where R: Read
     Ok(val)
 }
 
-fn read_decimal_long<R>(rd: &mut R) -> Result<BigInt, Error> where R: Read + BufRead {
+// Integers are `Value::Int` when they fit and `Value::Long` otherwise,
+// whatever opcode they come from.
+fn int<'a>(n: i64) -> Value<'a> {
+    if n as isize as i64 == n {
+        Value::Int(n as isize)
+    } else {
+        Value::Long(BigInt::from(n))
+    }
+}
+
+fn long<'a>(n: BigInt) -> Value<'a> {
+    match n.to_isize() {
+        Some(i) => Value::Int(i),
+        None => Value::Long(n),
+    }
+}
+
+fn read_decimal_long<'a, R>(rd: &mut R) -> Result<Value<'a>, Error> where R: Read + BufRead {
     let s = try!(read_until_newline(rd));
     let init = match s.split_last() {
         None => return Err(Error::InvalidLong),
@@ -103,29 +139,54 @@ fn read_decimal_long<R>(rd: &mut R) -> Result<BigInt, Error> where R: Read + Buf
         Some(_) => &s[..],
     };
 
+    if let Ok(n) = i64::from_ascii(init) {
+        return Ok(int(n))
+    }
     match BigInt::parse_bytes(&init, 10) {
-        Some(i) => Ok(i),
+        Some(i) => Ok(long(i)),
         None => Err(Error::InvalidLong)
     }
 }
 
+fn read_long<'a, I>(rd: &mut I, length: usize) -> Result<Value<'a>, Error> where I: Input<'a> {
+    // Up to 8 bytes are sign extended into an i64, no bytes at all are 0
+    if length <= 8 {
+        let mut buf = [0; 8];
+        try!(rd.read_exact(&mut buf[..length]));
+        if length > 0 && buf[length - 1] > 127 {
+            for b in &mut buf[length..] {
+                *b = 0xff;
+            }
+        }
+        return Ok(int(LittleEndian::read_i64(&buf)))
+    }
 
-fn read_long<R>(rd: &mut R, length: usize) -> Result<BigInt, Error> where R: Read + BufRead {
-    let mut buf = vec![0; length];
-    try!(rd.read_exact(buf.as_mut()));
+    // The length isn't trusted to preallocate
+    let buf = try!(rd.read_bytes(length));
 
     let mut n = BigInt::from_bytes_le(Sign::Plus, &buf);
 
-    let last = match buf.last_mut() {
+    let last = match buf.last() {
         None => return Err(Error::InvalidLong),
-        Some(last) => last,
+        Some(&last) => last,
     };
 
-    if *last > 127 {
+    if last > 127 {
         n = n - (1.to_bigint().unwrap() << (length * 8))  // TODO: remove unwrap()
     }
 
-    Ok(n)
+    Ok(long(n))
+}
+
+// Bytes which can't be borrowed are copied once, straight into an `Rc`.
+fn bytes<'a, I>(rd: &mut I, length: usize) -> Result<Bytes<'a>, Error> where I: Input<'a> {
+    Ok(try!(rd.read_bytes_with(length, Bytes::Borrowed, |s| Bytes::Shared(Rc::from(s)))))
+}
+
+// Validates UTF-8 before copying bytes which can't be borrowed.
+fn text<'a, I>(rd: &mut I, length: usize) -> Result<Text<'a>, Error> where I: Input<'a> {
+    let text = try!(rd.read_bytes_with(length, |s| str::from_utf8(s).map(Text::Borrowed), |s| str::from_utf8(s).map(|s| Text::Shared(Rc::from(s)))));
+    Ok(try!(text))
 }
 
 fn read_bracketed_string<R>(rd: &mut R) -> Result<Vec<u8>, Error> where R: Read + BufRead {
@@ -138,43 +199,359 @@ fn read_bracketed_string<R>(rd: &mut R) -> Result<Vec<u8>, Error> where R: Read
     Ok(try!(unescape(&s[1..s.len() - 1], false)))
 }
 
-pub struct Machine {
-    stack: Vec<Value>,
-    memo: HashMap<usize, Value>,
-    marker: Option<usize>,
+fn pairs(values: Vec<Value>) -> Result<Vec<(Value, Value)>, Error> {
+    if values.len() % 2 != 0 {
+        return Err(Error::InvalidValueOnStack)
+    }
+
+    let mut result = Vec::with_capacity(values.len() / 2);
+    let mut iter = values.into_iter();
+    while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+        result.push((key, value));
+    }
+    Ok(result)
+}
+
+fn ensure_hashable(value: &Value) -> Result<(), Error> {
+    if !value.is_hashable() {
+        return Err(Error::UnhashableValue)
+    }
+    Ok(())
+}
+
+fn set<'a, I>(items: I) -> Result<Set<'a>, Error> where I: IntoIterator<Item=Value<'a>> {
+    let mut set = Set::new();
+    for item in items {
+        try!(ensure_hashable(&item));
+        set.insert(item);
+    }
+    Ok(set)
+}
+
+fn set_items<'a, I>(dict: &mut Dict<'a>, items: I) -> Result<(), Error> where I: IntoIterator<Item=(Value<'a>, Value<'a>)> {
+    for (key, value) in items {
+        try!(ensure_hashable(&key));
+        dict.insert(key, value);
+    }
+    Ok(())
+}
+
+// Sets and frozensets are pickled as calls to their types before protocol 4
+fn reduce_builtin<'a>(class: &Value<'a>, args: &Value<'a>) -> Result<Option<Value<'a>>, Error> {
+    let name = match *class {
+        Value::Global { ref module, ref name } if module == "__builtin__" || module == "builtins" => name,
+        _ => return Ok(None),
+    };
+    if name != "set" && name != "frozenset" {
+        return Ok(None)
+    }
+
+    let args = match *args {
+        Value::Tuple(ref args) => args.borrow(),
+        _ => return Ok(None),
+    };
+    let items = match args.len() {
+        0 => Set::new(),
+        1 => match args[0] {
+            Value::List(ref items) | Value::Tuple(ref items) => try!(set(items.borrow().iter().cloned())),
+            Value::Set(ref items) | Value::FrozenSet(ref items) => items.borrow().clone(),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(if name == "set" {
+        Value::Set(rc!(items))
+    } else {
+        Value::FrozenSet(rc!(items))
+    }))
+}
+
+fn read_global<R>(rd: &mut R) -> Result<(String, String), Error> where R: Read + BufRead {
+    let module = try!(String::from_utf8(try!(read_until_newline(rd))));
+    let name = try!(String::from_utf8(try!(read_until_newline(rd))));
+    Ok((module, name))
 }
 
-impl Machine {
+pub struct Machine<'a> {
+    stack: Vec<Value<'a>>,
+    memo: Memo<Value<'a>>,
+    marks: Vec<usize>,
+    buffers: Option<VecDeque<Vec<u8>>>,
+    frame: Frame,
+    resolver: Option<Box<dyn ClassResolver>>,
+    // Results of the resolver, including the names it doesn't know
+    classes: HashMap<String, HashMap<String, Option<Rc<dyn Class>>>>,
+    // Stack positions of instances of resolved classes, for BUILD, with the
+    // memo indices they were stored at
+    instances: Vec<(usize, Rc<dyn Class>, Vec<usize>)>,
+    persistent_loader: Option<Box<dyn PersistentLoader>>,
+    extensions: ExtensionRegistry,
+    interner: Option<Interner>,
+}
+
+impl<'a> Machine<'a> {
     pub fn new() -> Self {
         Machine {
             stack: Vec::new(),
-            memo: HashMap::new(),
-            marker: None,
+            memo: Memo::new(),
+            marks: Vec::new(),
+            buffers: None,
+            frame: Frame::new(),
+            resolver: None,
+            classes: HashMap::new(),
+            instances: Vec::new(),
+            persistent_loader: None,
+            extensions: ExtensionRegistry::new(),
+            interner: None,
         }
     }
 
-    fn split_off(&mut self) -> Result<Vec<Value>, Error> {
-        let at = match self.marker {
+    /// Creates a machine which takes out-of-band buffers referenced by
+    /// NEXT_BUFFER from `buffers`, in order.
+    pub fn with_buffers<I>(buffers: I) -> Self where I: IntoIterator<Item=Vec<u8>> {
+        let mut machine = Machine::new();
+        machine.buffers = Some(buffers.into_iter().collect());
+        machine
+    }
+
+    /// Makes the machine consult `resolver` for every global it loads.
+    pub fn set_class_resolver<C>(&mut self, resolver: C) where C: ClassResolver + 'static {
+        self.resolver = Some(Box::new(resolver));
+    }
+
+    /// Makes the machine load objects referenced by persistent ids with
+    /// `loader`. Without one such pickles fail to load.
+    pub fn set_persistent_loader<P>(&mut self, loader: P) where P: PersistentLoader + 'static {
+        self.persistent_loader = Some(Box::new(loader));
+    }
+
+    /// Makes the machine look up EXT1, EXT2 and EXT4 codes in `extensions`.
+    pub fn set_extension_registry(&mut self, extensions: ExtensionRegistry) {
+        self.extensions = extensions;
+    }
+
+    /// Makes the machine deduplicate the strings it decodes with `interner`.
+    pub fn set_interner(&mut self, interner: Interner) {
+        self.interner = Some(interner);
+    }
+
+    fn push_string(&mut self, value: Value<'a>) {
+        let value = match self.interner {
+            None => value,
+            Some(ref interner) => interner.intern(value),
+        };
+        self.stack.push(value)
+    }
+
+    fn split_off(&mut self) -> Result<Vec<Value<'a>>, Error> {
+        let at = match self.marks.pop() {
             None => return Err(Error::EmptyMarker),
-            Some(marker) => marker,
+            Some(mark) => mark,
         };
 
         if at > self.stack.len() {
             return Err(Error::StackTooSmall);
         }
 
+        self.forget_instances(at);
         Ok(self.stack.split_off(at))
     }
 
-    fn pop(&mut self) -> Result<Value, Error> {
-        match self.stack.pop() {
+    // Values below the innermost mark are not accessible until it's popped,
+    // as if every MARK started a new stack.
+    fn bottom(&self) -> usize {
+        self.marks.last().cloned().unwrap_or(0)
+    }
+
+    fn top(&mut self) -> Result<&mut Value<'a>, Error> {
+        if self.stack.len() <= self.bottom() {
+            return Err(Error::EmptyStack)
+        }
+        match self.stack.last_mut() {
             None => Err(Error::EmptyStack),
             Some(value) => Ok(value),
         }
     }
 
+    fn pop(&mut self) -> Result<Value<'a>, Error> {
+        if self.stack.len() <= self.bottom() {
+            return Err(Error::EmptyStack)
+        }
+        match self.stack.pop() {
+            None => Err(Error::EmptyStack),
+            Some(value) => {
+                let len = self.stack.len();
+                self.forget_instances(len);
+                Ok(value)
+            },
+        }
+    }
+
+    fn forget_instances(&mut self, len: usize) {
+        while let Some(&(i, _, _)) = self.instances.last() {
+            if i < len {
+                break
+            }
+            self.instances.pop();
+        }
+    }
+
+    fn global(&mut self, module: String, name: String) -> Value<'a> {
+        if let Some(ref resolver) = self.resolver {
+            let known = self.classes.get(&module).map_or(false, |classes| classes.contains_key(&name));
+            if !known {
+                let class = resolver.find_class(&module, &name);
+                self.classes.entry(module.clone()).or_insert_with(HashMap::new).insert(name.clone(), class);
+            }
+        }
+        Value::Global { module: module, name: name }
+    }
+
+    fn push_extension(&mut self, code: i32) -> Result<(), Error> {
+        if code <= 0 {
+            return Err(Error::InvalidExtensionCode(code))
+        }
+
+        let (module, name) = match self.extensions.get_extension(code) {
+            None => return Err(Error::UnregisteredExtension(code)),
+            Some((module, name)) => (module.to_string(), name.to_string()),
+        };
+        let global = self.global(module, name);
+        self.stack.push(global);
+        Ok(())
+    }
+
+    fn find_class(&self, value: &Value) -> Option<Rc<dyn Class>> {
+        match *value {
+            Value::Global { ref module, ref name } => {
+                self.classes.get(module).and_then(|classes| classes.get(name)).and_then(|class| class.clone())
+            },
+            _ => None,
+        }
+    }
+
+    fn push_object(&mut self, construction: Construction, class: Value<'a>, args: Value<'a>, kwargs: Option<Value<'a>>) -> Result<(), Error> {
+        match self.find_class(&class) {
+            Some(class) => {
+                let instance = try!(class.construct(construction, args, kwargs));
+                self.stack.push(instance);
+                self.instances.push((self.stack.len() - 1, class, Vec::new()));
+            },
+            None => {
+                if construction == Construction::Reduce {
+                    if let Some(value) = try!(reduce_builtin(&class, &args)) {
+                        self.stack.push(value);
+                        return Ok(())
+                    }
+                }
+
+                let mut obj = Object::new(construction, class, args);
+                obj.kwargs = kwargs;
+                self.stack.push(Value::Object(rc!(obj)))
+            },
+        }
+        Ok(())
+    }
+
+    fn build(&mut self, state: Value<'a>) -> Result<(), Error> {
+        try!(self.top());
+        let top = self.stack.len() - 1;
+
+        let class = match self.instances.last_mut() {
+            Some(&mut (i, ref class, ref mut puts)) if i == top => Some((class.clone(), puts.split_off(0))),
+            _ => None,
+        };
+        if let Some((class, puts)) = class {
+            let instance = replace(&mut self.stack[top], Value::None);
+            let instance = try!(class.build(instance, state));
+            // Later GETs must see the built instance, not the old one
+            for i in puts {
+                self.memo.insert(i, instance.clone());
+            }
+            self.stack[top] = instance;
+            return Ok(())
+        }
+
+        match self.stack[top] {
+            Value::Object(ref mut obj) => (*obj.borrow_mut()).state = Some(state),
+            _ => return Err(Error::InvalidValueOnStack),
+        }
+        Ok(())
+    }
+
+    fn persistent_load(&mut self, pid: Value<'a>) -> Result<(), Error> {
+        let value = match self.persistent_loader {
+            None => return Err(Error::UnsupportedPersistentId),
+            Some(ref loader) => try!(loader.persistent_load(pid)),
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Executes the whole pickle and returns the resulting value.
+    pub fn load<R>(mut self, rd: &mut R) -> Result<Value<'a>, Error> where R: Read + BufRead {
+        loop {
+            if try!(self.execute(rd)) {
+                break
+            }
+        }
+        self.pop()
+    }
+
+    /// Executes the whole pickle in `buf` and returns the resulting value,
+    /// whose strings and bytes borrow from `buf` instead of being copied.
+    pub fn load_slice(mut self, buf: &'a [u8]) -> Result<Value<'a>, Error> {
+        let mut rd = Slice::new(buf);
+        loop {
+            if try!(self.execute_input(&mut rd)) {
+                break
+            }
+        }
+        self.pop()
+    }
+
+    /// Executes the whole pickle like `load`, and returns the resulting
+    /// value as a graph, see `unpickle_graph`.
+    pub fn load_graph<R>(mut self, rd: &mut R) -> Result<ValueGraph, Error> where R: Read + BufRead {
+        let mut result = Ok(false);
+        while let Ok(false) = result {
+            result = self.execute(rd);
+        }
+        let result = result.and_then(|_| self.pop());
+        // Values left in the memo and on the stack, or decoded before an
+        // error, may be cycles too
+        drop(ValueGraph::from_value(Value::List(rc!(self.into_values()))));
+        result.map(ValueGraph::from_value)
+    }
+
+    /// Executes the whole pickle in `buf` like `load_slice`, and records
+    /// its opcodes, so that it can be written again byte for byte.
+    pub fn load_recorded(mut self, buf: &'a [u8]) -> Result<(Value<'a>, Recording<'a>), Error> {
+        let mut rd = Slice::new(buf);
+        let mut recording = Recording::new();
+        loop {
+            let start = rd.position();
+            let memo_len = self.memo.len();
+            let stop = try!(self.execute_input(&mut rd));
+            recording.push(start, &buf[start..rd.position()], self.stack.last(), memo_len, rd.frame_end());
+            if stop {
+                break
+            }
+        }
+        let value = try!(self.pop());
+        Ok((value, recording))
+    }
+
+    // Values left on the stack and in the memo, e.g. after an error.
+    fn into_values(self) -> Vec<Value<'a>> {
+        let Machine { stack: mut values, mut memo, .. } = self;
+        memo.take_values(&mut values);
+        values
+    }
+
     fn handle_get(&mut self, i: usize) -> Result<(), Error> {
-        let value = match self.memo.get(&i) {
+        let value = match self.memo.get(i) {
             None => return Err(Error::InvalidGetValue),
             Some(ref v) => (*v).clone(),
         };
@@ -183,15 +560,30 @@ impl Machine {
     }
 
     fn handle_put(&mut self, i: usize) -> Result<(), Error> {
-        let value = match self.stack.last() {
-            None => return Err(Error::EmptyStack),
-            Some(ref v) => (*v).clone(),
-        };
+        let value = try!(self.top()).clone();
+        if self.memo.get(i).is_some() {
+            for &mut (_, _, ref mut puts) in &mut self.instances {
+                puts.retain(|&j| j != i);
+            }
+        }
+        let top = self.stack.len() - 1;
+        if let Some(&mut (j, _, ref mut puts)) = self.instances.last_mut() {
+            if j == top {
+                puts.push(i);
+            }
+        }
         self.memo.insert(i, value);
         Ok(())
     }
 
     pub fn execute<R>(&mut self, rd: &mut R) -> Result<bool, Error> where R: Read + BufRead {
+        let mut frame = replace(&mut self.frame, Frame::new());
+        let result = self.execute_input(&mut Framed::new(&mut frame, rd));
+        self.frame = frame;
+        result
+    }
+
+    fn execute_input<I>(&mut self, rd: &mut I) -> Result<bool, Error> where I: Input<'a> {
         macro_rules! ensure_not_negative {
             ($n: expr) => ({
                 if $n < Zero::zero() {
@@ -200,48 +592,60 @@ impl Machine {
             })
         }
 
+        macro_rules! ensure_fits_usize {
+            ($n: expr) => ({
+                if $n as usize as u64 != $n {
+                    return Err(Error::LengthTooLarge)
+                }
+            })
+        }
+
         match try!(rd.read_u8()) {
             PROTO => {
                 let version = try!(rd.read_u8());
-                if version < 2 || version > 2 {
+                if version < 2 || version > 5 {
                     return Err(Error::InvalidProto(version))
                 }
             },
+            FRAME => {
+                let length = try!(rd.read_u64::<LittleEndian>());
+                ensure_fits_usize!(length);
+                if rd.in_frame() {
+                    return Err(Error::UnfinishedFrame)
+                }
+                try!(rd.load_frame(length as usize));
+            },
             STOP => return Ok(true),
 
             INT => {
                 self.stack.push(match try!(read_decimal_int(rd)) {
                     BooleanOrInt::Boolean(v) => Value::Bool(v),
-                    BooleanOrInt::Int(v) => Value::Long(BigInt::from(v)), // FIXME: or int?
+                    BooleanOrInt::Int(v) => int(v),
                 })
             },
             BININT => self.stack.push(Value::Int(try!(rd.read_i32::<LittleEndian>()) as isize)),
             BININT1 => self.stack.push(Value::Int(try!(rd.read_u8()) as isize)),
             BININT2 => self.stack.push(Value::Int(try!(rd.read_u16::<LittleEndian>()) as isize)),
-            LONG => self.stack.push(Value::Long(BigInt::from(try!(read_decimal_long(rd))))),
+            LONG => self.stack.push(try!(read_decimal_long(rd))),
             LONG1 => {
                 let length = try!(rd.read_u8());
-                self.stack.push(Value::Long(BigInt::from(try!(read_long(rd, length as usize)))))
+                self.stack.push(try!(read_long(rd, length as usize)))
             }
             LONG4 => {
                 let length = try!(rd.read_i32::<LittleEndian>());
-                self.stack.push(Value::Long(BigInt::from(try!(read_long(rd, length as usize)))))
+                ensure_not_negative!(length);
+                self.stack.push(try!(read_long(rd, length as usize)))
             }
 
-            STRING => self.stack.push(Value::String(try!(read_bracketed_string(rd)))),
+            STRING => self.push_string(Value::String(Bytes::from(try!(read_bracketed_string(rd))))),
             BINSTRING => {
                 let length = try!(rd.read_i32::<LittleEndian>());
                 ensure_not_negative!(length);
-
-                let mut buf = vec![0; length as usize];
-                try!(rd.read_exact(&mut buf));
-                self.stack.push(Value::String(buf))
+                self.push_string(Value::String(try!(bytes(rd, length as usize))))
             },
             SHORT_BINSTRING => {
                 let length = try!(rd.read_u8());
-                let mut buf = vec![0; length as usize];
-                try!(rd.read_exact(&mut buf));
-                self.stack.push(Value::String(buf))
+                self.push_string(Value::String(try!(bytes(rd, length as usize))))
             },
 
             NONE => self.stack.push(Value::None),
@@ -250,14 +654,59 @@ impl Machine {
 
             UNICODE => {
                 let buf = try!(unescape(&try!(read_until_newline(rd)), true));
-                self.stack.push(Value::Unicode(try!(String::from_utf8(buf))))
+                self.push_string(Value::Unicode(Text::from(try!(String::from_utf8(buf)))))
             },
             BINUNICODE => {
                 let length = try!(rd.read_i32::<LittleEndian>());
                 ensure_not_negative!(length);
-                let mut buf = vec![0; length as usize];
-                try!(rd.read_exact(buf.as_mut()));
-                self.stack.push(Value::Unicode(try!(String::from_utf8(buf))))
+                self.push_string(Value::Unicode(try!(text(rd, length as usize))))
+            },
+            SHORT_BINUNICODE => {
+                let length = try!(rd.read_u8());
+                self.push_string(Value::Unicode(try!(text(rd, length as usize))))
+            },
+            BINUNICODE8 => {
+                let length = try!(rd.read_u64::<LittleEndian>());
+                ensure_fits_usize!(length);
+                self.push_string(Value::Unicode(try!(text(rd, length as usize))))
+            },
+
+            BINBYTES => {
+                let length = try!(rd.read_u32::<LittleEndian>());
+                self.stack.push(Value::Bytes(try!(bytes(rd, length as usize))))
+            },
+            SHORT_BINBYTES => {
+                let length = try!(rd.read_u8());
+                self.stack.push(Value::Bytes(try!(bytes(rd, length as usize))))
+            },
+            BINBYTES8 => {
+                let length = try!(rd.read_u64::<LittleEndian>());
+                ensure_fits_usize!(length);
+                self.stack.push(Value::Bytes(try!(bytes(rd, length as usize))))
+            },
+
+            BYTEARRAY8 => {
+                let length = try!(rd.read_u64::<LittleEndian>());
+                ensure_fits_usize!(length);
+                self.stack.push(Value::ByteArray(try!(rd.read_bytes(length as usize)).into_owned()))
+            },
+            NEXT_BUFFER => {
+                let buf = match self.buffers {
+                    None => return Err(Error::MissingBuffers),
+                    Some(ref mut buffers) => match buffers.pop_front() {
+                        None => return Err(Error::NotEnoughBuffers),
+                        Some(buf) => buf,
+                    },
+                };
+                self.stack.push(Value::ByteArray(buf))
+            },
+            READONLY_BUFFER => {
+                let value = try!(self.pop());
+                self.stack.push(match value {
+                    Value::ByteArray(buf) => Value::Bytes(Bytes::from(buf)),
+                    Value::Bytes(buf) => Value::Bytes(buf),
+                    _ => return Err(Error::InvalidValueOnStack),
+                })
             },
 
             FLOAT => {
@@ -273,17 +722,17 @@ impl Machine {
             },
             APPEND => {
                 let v = try!(self.pop());
-                match self.stack.last_mut() {
-                    None => return Err(Error::EmptyStack),
-                    Some(&mut Value::List(ref mut list)) => (*list.borrow_mut()).push(v),
+                match try!(self.top()) {
+                    &mut Value::List(ref mut list) => (*list.borrow_mut()).push(v),
+                    &mut Value::Object(ref mut obj) => (*obj.borrow_mut()).list_items.push(v),
                     _ => return Err(Error::InvalidValueOnStack),
                 }
             },
             APPENDS => {
                 let values = try!(self.split_off());
-                match self.stack.last_mut() {
-                    None => return Err(Error::EmptyStack),
-                    Some(&mut Value::List(ref mut list)) => (*list.borrow_mut()).extend(values),
+                match try!(self.top()) {
+                    &mut Value::List(ref mut list) => (*list.borrow_mut()).extend(values),
+                    &mut Value::Object(ref mut obj) => (*obj.borrow_mut()).list_items.extend(values),
                     _ => return Err(Error::InvalidValueOnStack),
                 }
             },
@@ -302,66 +751,72 @@ impl Machine {
                 self.stack.push(Value::Tuple(rc!(vec![v1])))
             },
             TUPLE2 => {
-                let v1 = try!(self.pop());
                 let v2 = try!(self.pop());
+                let v1 = try!(self.pop());
                 self.stack.push(Value::Tuple(rc!(vec![v1, v2])))
             },
             TUPLE3 => {
-                let v1 = try!(self.pop());
-                let v2 = try!(self.pop());
                 let v3 = try!(self.pop());
+                let v2 = try!(self.pop());
+                let v1 = try!(self.pop());
                 self.stack.push(Value::Tuple(rc!(vec![v1, v2, v3])))
             }
 
-            EMPTY_DICT => self.stack.push(Value::Dict(rc!(Vec::new()))),
+            EMPTY_DICT => self.stack.push(Value::Dict(rc!(Dict::new()))),
             DICT => {
-                let mut values = try!(self.split_off());
-                let mut dict = Vec::new();
-
-                for i in 0 .. values.len() / 2 { // TODO: Check panic
-                    let key = values.remove(2 * i);
-                    let value = values.remove(2 * i + 1);
-                    dict.push((key, value));
-                }
+                let mut dict = Dict::new();
+                try!(set_items(&mut dict, try!(pairs(try!(self.split_off())))));
                 self.stack.push(Value::Dict(rc!(dict)));
             },
             SETITEM => {
                 let value = try!(self.pop());
                 let key = try!(self.pop());
-                match self.stack.last_mut() {
-                    None => return Err(Error::EmptyStack),
-                    Some(&mut Value::Dict(ref mut dict)) => (*dict.borrow_mut()).push((key, value)),
+                match try!(self.top()) {
+                    &mut Value::Dict(ref mut dict) => try!(set_items(&mut dict.borrow_mut(), Some((key, value)))),
+                    &mut Value::Object(ref mut obj) => (*obj.borrow_mut()).dict_items.push((key, value)),
                     _ => return Err(Error::InvalidValueOnStack),
                 }
             },
             SETITEMS => {
-                let mut values = try!(self.split_off());
-
-                match self.stack.last_mut() {
-                    None => return Err(Error::EmptyStack),
-                    Some(&mut Value::Dict(ref mut dict_ref)) => {
-                        for i in 0 .. values.len() / 2 { // TODO: Check panic
-                                let key = values.remove(2 * i);
-                                let value = values.remove(2 * i + 1);
-                                (*dict_ref.borrow_mut()).push((key, value));
-                            }
-                    },
+                let items = try!(pairs(try!(self.split_off())));
+
+                match try!(self.top()) {
+                    &mut Value::Dict(ref mut dict_ref) => try!(set_items(&mut dict_ref.borrow_mut(), items)),
+                    &mut Value::Object(ref mut obj) => (*obj.borrow_mut()).dict_items.extend(items),
+                    _ => return Err(Error::InvalidValueOnStack),
+                }
+            },
+
+            EMPTY_SET => self.stack.push(Value::Set(rc!(Set::new()))),
+            ADDITEMS => {
+                let values = try!(self.split_off());
+                for value in &values {
+                    try!(ensure_hashable(value));
+                }
+                match try!(self.top()) {
+                    &mut Value::Set(ref mut set) => (*set.borrow_mut()).extend(values),
                     _ => return Err(Error::InvalidValueOnStack),
                 }
             },
+            FROZENSET => {
+                let values = try!(self.split_off());
+                self.stack.push(Value::FrozenSet(rc!(try!(set(values)))));
+            },
 
             POP => {
-                try!(self.pop());
+                // Like in CPython, POP right after MARK discards the mark
+                if self.marks.last() == Some(&self.stack.len()) {
+                    try!(self.split_off());
+                } else {
+                    try!(self.pop());
+                }
             },
             DUP => {
-                let value = match self.stack.last() {
-                    None => return Err(Error::EmptyStack),
-                    Some(ref v) => (*v).clone(),
-                };
+                let value = try!(self.top()).clone();
                 self.stack.push(value)
             },
             MARK => {
-                self.marker = Some(self.stack.len())
+                self.marks.push(self.stack.len())
             },
             POP_MARK => {
                 try!(self.split_off());
@@ -402,6 +857,74 @@ impl Machine {
                 ensure_not_negative!(n);
                 try!(self.handle_put(n as usize))
             }
+            MEMOIZE => {
+                let n = self.memo.len();
+                try!(self.handle_put(n))
+            }
+
+            EXT1 => try!(self.push_extension(try!(rd.read_u8()) as i32)),
+            EXT2 => try!(self.push_extension(try!(rd.read_u16::<LittleEndian>()) as i32)),
+            EXT4 => try!(self.push_extension(try!(rd.read_i32::<LittleEndian>()))),
+
+            GLOBAL => {
+                let (module, name) = try!(read_global(rd));
+                let global = self.global(module, name);
+                self.stack.push(global)
+            },
+            STACK_GLOBAL => {
+                let name = try!(self.pop());
+                let module = try!(self.pop());
+                match (module, name) {
+                    (Value::Unicode(module), Value::Unicode(name)) => {
+                        let global = self.global(module.into_string(), name.into_string());
+                        self.stack.push(global)
+                    },
+                    _ => return Err(Error::InvalidValueOnStack),
+                }
+            },
+            REDUCE => {
+                let args = try!(self.pop());
+                let class = try!(self.pop());
+                try!(self.push_object(Construction::Reduce, class, args, None))
+            },
+            BUILD => {
+                let state = try!(self.pop());
+                try!(self.build(state))
+            },
+            INST => {
+                let (module, name) = try!(read_global(rd));
+                let args = try!(self.split_off());
+                let class = self.global(module, name);
+                try!(self.push_object(Construction::Inst, class, Value::Tuple(rc!(args)), None))
+            },
+            OBJ => {
+                let mut args = try!(self.split_off());
+                if args.is_empty() {
+                    return Err(Error::StackTooSmall)
+                }
+                let class = args.remove(0);
+                try!(self.push_object(Construction::Obj, class, Value::Tuple(rc!(args)), None))
+            },
+            NEWOBJ => {
+                let args = try!(self.pop());
+                let class = try!(self.pop());
+                try!(self.push_object(Construction::NewObj, class, args, None))
+            },
+            NEWOBJ_EX => {
+                let kwargs = try!(self.pop());
+                let args = try!(self.pop());
+                let class = try!(self.pop());
+                try!(self.push_object(Construction::NewObjEx, class, args, Some(kwargs)))
+            },
+
+            PERSID => {
+                let pid = try!(String::from_utf8(try!(read_until_newline(rd))));
+                try!(self.persistent_load(Value::Unicode(Text::from(pid))))
+            },
+            BINPERSID => {
+                let pid = try!(self.pop());
+                try!(self.persistent_load(pid))
+            },
 
             c => return Err(Error::UnknownOpcode(c)),
         }
@@ -409,24 +932,48 @@ impl Machine {
     }
 }
 
-pub fn unpickle<R>(rd: &mut R) -> Result<Value, Error> where R: Read + BufRead {
-    let mut machine = Machine::new();
-    loop {
-        if try!(machine.execute(rd)) {
-            break
-        }
-    }
-    Ok(try!(machine.pop()))
+pub fn unpickle<R>(rd: &mut R) -> Result<Value<'static>, Error> where R: Read + BufRead {
+    Machine::new().load(rd)
+}
+
+/// Like `unpickle`, but strings and bytes of the result borrow from `buf`,
+/// which may as well be a memory-mapped file.
+pub fn from_slice<'a>(buf: &'a [u8]) -> Result<Value<'a>, Error> {
+    Machine::new().load_slice(buf)
+}
+
+/// Like `from_slice`, but also records how the pickle was written, see
+/// `Recording`.
+pub fn record<'a>(buf: &'a [u8]) -> Result<(Value<'a>, Recording<'a>), Error> {
+    Machine::new().load_recorded(buf)
+}
+
+/// Like `unpickle`, but returns a graph which frees cyclic values when
+/// dropped.
+pub fn unpickle_graph<R>(rd: &mut R) -> Result<ValueGraph, Error> where R: Read + BufRead {
+    Machine::new().load_graph(rd)
+}
+
+/// Like `unpickle`, but takes the out-of-band buffers of a protocol 5
+/// pickle from `buffers`, as `pickle.loads(data, buffers=...)` does.
+pub fn unpickle_with_buffers<R, I>(rd: &mut R, buffers: I) -> Result<Value<'static>, Error>
+    where R: Read + BufRead, I: IntoIterator<Item=Vec<u8>> {
+    Machine::with_buffers(buffers).load(rd)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::{Cell};
     use std::io::{Cursor};
+    use std::rc::{Rc};
 
-    use num::{FromPrimitive};
+    use num::bigint::{BigInt};
 
-    use super::{Error, unpickle};
-    use super::super::value::{Value};
+    use super::{Error, Machine, unpickle, unpickle_graph, unpickle_with_buffers, from_slice};
+    use super::super::value::{Value, Bytes, Text, Construction, Set};
+    use super::super::graph::{ValueGraph, Node};
+    use super::super::class::{Class};
+    use super::super::extension::{ExtensionRegistry};
 
     macro_rules! t {
         ($buffer: expr, $pat:pat, $result:expr) => ({
@@ -452,17 +999,31 @@ mod tests {
         })
     }
 
-    macro_rules! n {
-        ($x: expr) => ({FromPrimitive::from_isize($x).unwrap()})
-    }
-
     #[test]
     fn test_int() {
-        t!(b"I1\n.", Value::Long(n), assert_eq!(n, n!(1)));
+        t!(b"I1\n.", Value::Int(n), assert_eq!(n, 1));
         t!(b"K\x01.", Value::Int(n), assert_eq!(n, 1));
         t!(b"\x80\x02K\x01.", Value::Int(n), assert_eq!(n, 1));
     }
 
+    #[test]
+    fn test_long() {
+        t!(b"\x80\x02\x8a\x01\xff.", Value::Int(-1), ());
+        t!(b"\x80\x02\x8a\x02\xff\x00.", Value::Int(255), ());
+        t!(b"\x80\x02\x8a\x08\xff\xff\xff\xff\xff\xff\xff\x7f.", Value::Int(n), assert_eq!(n as i64, i64::max_value()));
+        t!(b"\x80\x02\x8a\x08\x00\x00\x00\x00\x00\x00\x00\x80.", Value::Int(n), assert_eq!(n as i64, i64::min_value()));
+        t!(b"\x80\x02\x8b\x02\x00\x00\x00\x01\x00.", Value::Int(1), ());
+        t!(b"\x80\x02\x8a\x00.", Value::Int(0), ());
+        t!(b"\x80\x02\x8b\x00\x00\x00\x00.", Value::Int(0), ());
+        t!(b"L12L\n.", Value::Int(12), ());
+
+        let big = BigInt::from(1) << 70;
+        t!(b"\x80\x02\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00@.", Value::Long(n), assert_eq!(n, big));
+        t!(b"\x80\x02\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00\xc0.", Value::Long(n), assert_eq!(n, -big.clone()));
+        t!(b"L1180591620717411303424L\n.", Value::Long(n), assert_eq!(n, big));
+        t!(b"\x80\x02\x8a\t\x00\x00\x00\x00\x00\x00\x00\x80\x00.", ref n, assert_eq!(n.to_bigint(), Some(BigInt::from(1) << 63)));
+    }
+
     #[test]
     fn test_const() {
         t!(b"I00\n.", Value::Bool(false), ());
@@ -476,21 +1037,559 @@ mod tests {
 
     #[test]
     fn test_string() {
-        t!(b"S''\np1\n.", Value::String(s), assert_eq!(s, b""));
-        t!(b"S'foo'\np1\n.", Value::String(s), assert_eq!(s, b"foo"));
-        t!(b"U\x03fooq\x01.", Value::String(s), assert_eq!(s, b"foo"));
-        t!(b"\x80\x02U\x03fooq\x01.", Value::String(s), assert_eq!(s, b"foo"));
+        t!(b"S''\np1\n.", Value::String(s), assert_eq!(&s[..], b""));
+        t!(b"S'foo'\np1\n.", Value::String(s), assert_eq!(&s[..], b"foo"));
+        t!(b"U\x03fooq\x01.", Value::String(s), assert_eq!(&s[..], b"foo"));
+        t!(b"\x80\x02U\x03fooq\x01.", Value::String(s), assert_eq!(&s[..], b"foo"));
 
-        t!(b"S'\\n'\np1\n.", Value::String(s), assert_eq!(s, b"\n"));
+        t!(b"S'\\n'\np1\n.", Value::String(s), assert_eq!(&s[..], b"\n"));
     }
 
     #[test]
     fn test_unicode() {
         t!(b"Vfoo\np1\n.", Value::Unicode(s), assert_eq!(s, "foo"));
+        // Raw bytes are Latin-1, like Python decodes them
+        t!(b"V\xe2\x28\xa1\n.", Value::Unicode(s), assert_eq!(s, "\u{e2}(\u{a1}"));
         t!(b"X\x03\x00\x00\x00fooq\x01.", Value::Unicode(s), assert_eq!(s, "foo"));
         t!(b"\x80\x02X\x03\x00\x00\x00fooq\x01.", Value::Unicode(s), assert_eq!(s, "foo"));
     }
 
+    #[test]
+    fn test_bytes() {
+        t!(b"\x80\x03C\x00q\x00.", Value::Bytes(s), assert_eq!(&s[..], b""));
+        t!(b"\x80\x03C\x03fooq\x00.", Value::Bytes(s), assert_eq!(&s[..], b"foo"));
+        t!(b"\x80\x03B\x03\x00\x00\x00fooq\x00.", Value::Bytes(s), assert_eq!(&s[..], b"foo"));
+        t!(b"\x80\x04\x8e\x03\x00\x00\x00\x00\x00\x00\x00foo\x94.", Value::Bytes(s), assert_eq!(&s[..], b"foo"));
+    }
+
+    #[test]
+    fn test_byte_array() {
+        t!(b"\x80\x05\x95\x0e\x00\x00\x00\x00\x00\x00\x00\x96\x03\x00\x00\x00\x00\x00\x00\x00abc\x94.", Value::ByteArray(s), assert_eq!(s, b"abc"));
+    }
+
+    #[test]
+    fn test_shared_strings() {
+        // k = 'record_key'; [k, k, b'v', b'v']
+        t!(b"\x80\x03]q\x00(X\n\x00\x00\x00record_keyq\x01h\x01C\x01vq\x02h\x02e.", Value::List(items), {
+            match &items.borrow()[..] {
+                &[Value::Unicode(Text::Shared(ref a)), Value::Unicode(Text::Shared(ref b)),
+                  Value::Bytes(Bytes::Shared(ref c)), Value::Bytes(Bytes::Shared(ref d))] => {
+                    assert!(Rc::ptr_eq(a, b));
+                    assert!(Rc::ptr_eq(c, d));
+                },
+                _ => assert!(false),
+            }
+        });
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let buffer = b"\x80\x04\x95\x16\x00\x00\x00\x00\x00\x00\x00]\x94(C\x02ab\x94\x8c\x02cd\x94B\x01\x00\x00\x00x\x94e.";
+        let borrowed = |value: &Value| {
+            let start = buffer.as_ptr() as usize;
+            let ptr = match *value {
+                Value::Bytes(ref s) if s.is_borrowed() => s.as_ptr(),
+                Value::Unicode(ref s) if s.is_borrowed() => s.as_ptr(),
+                _ => return false,
+            } as usize;
+            ptr >= start && ptr < start + buffer.len()
+        };
+        match from_slice(&buffer[..]) {
+            Ok(Value::List(ref items)) => {
+                let items = items.borrow();
+                assert!(items.iter().all(|item| borrowed(item)));
+                assert_eq!(items[0], Value::Bytes(b"ab"[..].into()));
+                assert_eq!(items[1], Value::Unicode("cd".into()));
+                assert_eq!(items[2], Value::Bytes(b"x"[..].into()));
+            },
+            _ => assert!(false),
+        }
+
+        match from_slice(&b"\x80\x02}q\x00X\x03\x00\x00\x00keyq\x01X\x05\x00\x00\x00caf\xc3\xa9q\x02s."[..]) {
+            Ok(Value::Dict(ref d)) => assert_eq!(d.borrow().get(&Value::Unicode("key".into())), Some(&Value::Unicode("caf\u{e9}".into()))),
+            _ => assert!(false),
+        }
+
+        match from_slice(&b"\x80\x03X\x01\x00\x00\x00\xff."[..]) {
+            Err(Error::UnicodeError) => (),
+            _ => assert!(false),
+        }
+        match from_slice(&b"\x80\x04\x95\x10\x00\x00\x00\x00\x00\x00\x00C\x02a"[..]) {
+            Err(Error::Io(_)) => (),
+            _ => assert!(false),
+        }
+        // Straddles the end of the frame
+        match from_slice(&b"\x80\x04\x95\x03\x00\x00\x00\x00\x00\x00\x00C\x02ab."[..]) {
+            Err(Error::Io(_)) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_buffers() {
+        let buffer = b"\x80\x05\x95\x08\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x97\x98e.";
+        let buffers = vec![b"ab".to_vec(), b"cd".to_vec()];
+        match unpickle_with_buffers(&mut Cursor::new(&buffer[..]), buffers) {
+            Ok(Value::List(l)) => {
+                let l = l.borrow();
+                assert_eq!(l.len(), 2);
+                match (&l[0], &l[1]) {
+                    (&Value::ByteArray(ref a), &Value::Bytes(ref b)) => {assert_eq!(a, b"ab"); assert_eq!(&b[..], b"cd")},
+                    _ => assert!(false),
+                }
+            },
+            _ => assert!(false),
+        }
+
+        match unpickle_with_buffers(&mut Cursor::new(&buffer[..]), vec![b"ab".to_vec()]) {
+            Err(Error::NotEnoughBuffers) => (),
+            _ => assert!(false),
+        }
+        e!(buffer, Error::MissingBuffers);
+    }
+
+    #[test]
+    fn test_proto4() {
+        t!(b"\x80\x04\x95\x07\x00\x00\x00\x00\x00\x00\x00\x8c\x03foo\x94.", Value::Unicode(s), assert_eq!(s, "foo"));
+        t!(b"\x80\x04\x8d\x03\x00\x00\x00\x00\x00\x00\x00foo\x94.", Value::Unicode(s), assert_eq!(s, "foo"));
+        t!(b"\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x01a\x94h\x01e.", Value::List(l), {
+            let l = l.borrow();
+            assert_eq!(l.len(), 2);
+            match (&l[0], &l[1]) {
+                (&Value::Unicode(ref a), &Value::Unicode(ref b)) => {assert_eq!(a, "a"); assert_eq!(b, "a")},
+                _ => assert!(false),
+            }
+        });
+    }
+
+    #[test]
+    fn test_builtin_set() {
+        t!(b"\x80\x02c__builtin__\nset\nq\x00]q\x01(K\x01K\x02e\x85q\x02Rq\x03.", Value::Set(s), {
+            let s = s.borrow();
+            assert_eq!(s.len(), 2);
+            assert!(s.contains(&Value::Int(1)));
+            assert!(s.contains(&Value::Float(2.0)));
+        });
+        t!(b"\x80\x02c__builtin__\nfrozenset\nq\x00]q\x01\x85q\x02Rq\x03.", Value::FrozenSet(s), assert!(s.borrow().is_empty()));
+        t!(b"c__builtin__\nset\np0\n((lp1\nI1\naI2\natp2\nRp3\n.", Value::Set(s), assert_eq!(s.borrow().len(), 2));
+        t!(b"\x80\x03cbuiltins\nfrozenset\n)R.", Value::FrozenSet(s), assert!(s.borrow().is_empty()));
+        t!(b"\x80\x02c__builtin__\nset\n]K\x01K\x01\x86R.", Value::Object(_), ());
+        e!(b"\x80\x02c__builtin__\nset\n]]a\x85R.", Error::UnhashableValue);
+    }
+
+    #[test]
+    fn test_frame() {
+        t!(b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00N.", Value::None, ());
+        e!(b"\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00\x95\x01\x00\x00\x00\x00\x00\x00\x00N.", Error::UnfinishedFrame);
+        e!(b"\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00N.", Error::Io(_));
+        // Arguments can't straddle the end of a frame
+        e!(b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00J\x01\x00\x00\x00.", Error::Read(_));
+        e!(b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00I1\n.", Error::Io(_));
+    }
+
+    #[test]
+    fn test_memo() {
+        t!(b"I5\np99999999\n0g99999999\n.", Value::Int(5), ());
+        t!(b"\x80\x04N\x94K\x01\x94h\x01.", Value::Int(1), ());
+        e!(b"I5\np99999999\n0g99999998\n.", Error::InvalidGetValue);
+    }
+
+    #[test]
+    fn test_set() {
+        t!(b"\x80\x04\x95\t\x00\x00\x00\x00\x00\x00\x00\x8f\x94(K\x01K\x02\x90.", Value::Set(s), assert_eq!(s.borrow().len(), 2));
+        t!(b"\x80\x04\x95\x06\x00\x00\x00\x00\x00\x00\x00(K\x03\x91\x94.", Value::FrozenSet(s), assert_eq!(s.borrow().len(), 1));
+        t!(b"\x80\x04\x8f(K\x01\x88G?\xf0\x00\x00\x00\x00\x00\x00\x90.", Value::Set(s), assert_eq!(s.borrow().len(), 1));
+        t!(b"\x80\x04(K\x01K\x01\x91.", Value::FrozenSet(s), assert_eq!(s.borrow().len(), 1));
+        e!(b"\x80\x04\x8f(]\x90.", Error::UnhashableValue);
+        e!(b"\x80\x04(]\x91.", Error::UnhashableValue);
+    }
+
+    #[test]
+    fn test_dict() {
+        t!(b"(dp0\nI1\nVa\np1\nsF1.0\nVb\np2\ns.", Value::Dict(d), {
+            let d = d.borrow();
+            assert_eq!(d.len(), 1);
+            match d.iter().next() {
+                Some(&(Value::Int(1), Value::Unicode(ref v))) => assert_eq!(v, "b"),
+                _ => assert!(false),
+            }
+        });
+        t!(b"\x80\x02}q\x00(K\x01K\x02\x86q\x01X\x01\x00\x00\x00aq\x02U\x01aK\x03u.", Value::Dict(d), {
+            let d = d.borrow();
+            assert_eq!(d.len(), 2);
+            assert_eq!(d.get(&Value::String(b"a"[..].into())), Some(&Value::Int(3)));
+            assert_eq!(d.get(&Value::Unicode("a".into())), None);
+        });
+        t!(b"(K\x01K\x02K\x01K\x03d.", Value::Dict(d), assert_eq!(d.borrow().get(&Value::Int(1)), Some(&Value::Int(3))));
+        e!(b"}]K\x01s.", Error::UnhashableValue);
+        e!(b"}(]K\x01u.", Error::UnhashableValue);
+    }
+
+    #[test]
+    fn test_tuple() {
+        t!(b"K\x01K\x02\x86.", Value::Tuple(t), match &t.borrow()[..] {
+            &[Value::Int(1), Value::Int(2)] => (),
+            _ => assert!(false),
+        });
+        t!(b"K\x01K\x02K\x03\x87.", Value::Tuple(t), match &t.borrow()[..] {
+            &[Value::Int(1), Value::Int(2), Value::Int(3)] => (),
+            _ => assert!(false),
+        });
+    }
+
+    #[test]
+    fn test_nested_marks() {
+        t!(b"]q\x00((K\x01K\x02K\x03K\x04tq\x01(K\x05K\x06K\x07K\x08tq\x02e.", Value::List(l), {
+            let l = l.borrow();
+            assert_eq!(l.len(), 2);
+            match (&l[0], &l[1]) {
+                (&Value::Tuple(ref a), &Value::Tuple(ref b)) => {assert_eq!(a.borrow().len(), 4); assert_eq!(b.borrow().len(), 4)},
+                _ => assert!(false),
+            }
+        });
+        t!(b"(dp0\nVa\np1\n(lp2\n(I1\nI2\nI3\nI4\ntp3\nasVb\np4\nI2\ns.", Value::Dict(d), {
+            let d = d.borrow();
+            assert_eq!(d.len(), 2);
+            match d.get(&Value::Unicode("a".into())) {
+                Some(&Value::List(ref v)) => assert_eq!(v.borrow().len(), 1),
+                _ => assert!(false),
+            }
+        });
+        t!(b"\x80\x04\x95-\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x01a\x94]\x94(K\x01\x8c\x02xy\x94C\x01z\x94e\x8c\x01b\x94\x8f\x94(K\x01K\x02\x90\x8c\x01c\x94(K\x03\x91\x94u.", Value::Dict(d), {
+            let d = d.borrow();
+            assert_eq!(d.len(), 3);
+            let get = |key: &'static str| d.get(&Value::Unicode(key.into()));
+            match (get("a"), get("b"), get("c")) {
+                (Some(&Value::List(ref a)), Some(&Value::Set(ref b)), Some(&Value::FrozenSet(ref c))) => {
+                    assert_eq!(a.borrow().len(), 3);
+                    assert_eq!(b.borrow().len(), 2);
+                    assert_eq!(c.borrow().len(), 1);
+                },
+                _ => assert!(false),
+            }
+        });
+    }
+
+    #[test]
+    fn test_marks() {
+        t!(b"K\x01(K\x02K\x031.", Value::Int(1), ());
+        t!(b"K\x01(0.", Value::Int(1), ());
+        e!(b"](K\x01a.", Error::EmptyStack);
+        e!(b"K\x01(\x85.", Error::EmptyStack);
+        e!(b"K\x01(.", Error::EmptyStack);
+        e!(b"K\x01t.", Error::EmptyMarker);
+        e!(b"(K\x01tt.", Error::EmptyMarker);
+    }
+
+    macro_rules! global {
+        ($value: expr, $module: expr, $name: expr) => ({
+            match $value {
+                Value::Global { ref module, ref name } => {assert_eq!(module, $module); assert_eq!(name, $name)},
+                _ => assert!(false),
+            }
+        })
+    }
+
+    #[test]
+    fn test_global() {
+        t!(b"cmodule\nname\n.", v, global!(v, "module", "name"));
+        t!(b"\x80\x04\x8c\x06module\x8c\x04name\x93.", v, global!(v, "module", "name"));
+        e!(b"\x80\x04K\x01\x8c\x04name\x93.", Error::InvalidValueOnStack);
+    }
+
+    #[test]
+    fn test_object() {
+        // Protocol 0 object of an old-style class
+        t!(b"ccopy_reg\n_reconstructor\np0\n(c__main__\nFoo\np1\nc__builtin__\nobject\np2\nNtp3\nRp4\n(dp5\nVa\np6\nI1\nsb.", Value::Object(obj), {
+            let obj = obj.borrow();
+            assert_eq!(obj.construction, Construction::Reduce);
+            global!(obj.class, "copy_reg", "_reconstructor");
+            match obj.args {
+                Value::Tuple(ref args) => {
+                    let args = args.borrow();
+                    assert_eq!(args.len(), 3);
+                    global!(args[0], "__main__", "Foo");
+                    global!(args[1], "__builtin__", "object");
+                },
+                _ => assert!(false),
+            }
+            match obj.state {
+                Some(Value::Dict(ref d)) => assert_eq!(d.borrow().len(), 1),
+                _ => assert!(false),
+            }
+        });
+
+        // Protocol 2 object of a new-style class
+        t!(b"\x80\x02c__main__\nFoo\nq\x00)\x81q\x01}q\x02X\x01\x00\x00\x00aq\x03K\x01sb.", Value::Object(obj), {
+            let obj = obj.borrow();
+            assert_eq!(obj.construction, Construction::NewObj);
+            global!(obj.class, "__main__", "Foo");
+            assert!(obj.state.is_some());
+        });
+
+        // Protocol 4 uses STACK_GLOBAL
+        t!(b"\x80\x04\x95!\x00\x00\x00\x00\x00\x00\x00\x8c\x08__main__\x94\x8c\x03Foo\x94\x93\x94)\x81\x94}\x94\x8c\x01a\x94K\x01sb.", Value::Object(obj), {
+            global!(obj.borrow().class, "__main__", "Foo");
+        });
+
+        // __reduce__ with arguments
+        t!(b"\x80\x02c__main__\nBar\nq\x00K\x01K\x02\x86q\x01Rq\x02.", Value::Object(obj), {
+            match obj.borrow().args {
+                Value::Tuple(ref args) => match &args.borrow()[..] {
+                    &[Value::Int(1), Value::Int(2)] => (),
+                    _ => assert!(false),
+                },
+                _ => assert!(false),
+            }
+        });
+
+        // Dict items of a dict subclass
+        t!(b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01X\x01\x00\x00\x00aq\x02K\x01s.", Value::Object(obj), {
+            assert_eq!(obj.borrow().dict_items.len(), 1);
+        });
+
+        // INST and OBJ
+        t!(b"(I1\nimodule\nname\n.", Value::Object(obj), {
+            let obj = obj.borrow();
+            assert_eq!(obj.construction, Construction::Inst);
+            global!(obj.class, "module", "name");
+        });
+        t!(b"(cmodule\nname\nK\x01o.", Value::Object(obj), {
+            let obj = obj.borrow();
+            assert_eq!(obj.construction, Construction::Obj);
+            global!(obj.class, "module", "name");
+        });
+        e!(b"(o.", Error::StackTooSmall);
+
+        t!(b"\x80\x04\x8c\x06module\x8c\x04name\x93)}\x92.", Value::Object(obj), {
+            let obj = obj.borrow();
+            assert_eq!(obj.construction, Construction::NewObjEx);
+            assert!(obj.kwargs.is_some());
+        });
+    }
+
+    struct Point;
+
+    impl Class for Point {
+        fn construct<'a>(&self, construction: Construction, args: Value<'a>, _: Option<Value<'a>>) -> Result<Value<'a>, Error> {
+            assert_eq!(construction, Construction::NewObj);
+            Ok(args)
+        }
+
+        fn build<'a>(&self, instance: Value<'a>, state: Value<'a>) -> Result<Value<'a>, Error> {
+            match (instance, state) {
+                (Value::Tuple(_), Value::Tuple(state)) => Ok(Value::Tuple(state)),
+                _ => Err(Error::InvalidValueOnStack),
+            }
+        }
+    }
+
+    fn first<'a>(_: Construction, args: Value<'a>, _: Option<Value<'a>>) -> Result<Value<'a>, Error> {
+        match args {
+            Value::Tuple(args) => Ok(args.borrow()[0].clone()),
+            _ => Err(Error::InvalidValueOnStack),
+        }
+    }
+
+    fn resolve(module: &str, name: &str) -> Option<Rc<dyn Class>> {
+        match (module, name) {
+            ("geometry", "Point") => Some(Rc::new(Point)),
+            ("builtins", "str") => Some(Rc::new(first)),
+            _ => None,
+        }
+    }
+
+    macro_rules! r {
+        ($buffer: expr) => ({
+            let mut machine = Machine::new();
+            machine.set_class_resolver(resolve);
+            machine.load(&mut Cursor::new(&$buffer[..]))
+        })
+    }
+
+    #[test]
+    fn test_class_resolver() {
+        match r!(b"\x80\x04\x8c\x08builtins\x8c\x03str\x93\x8c\x03foo\x85R.") {
+            Ok(Value::Unicode(s)) => assert_eq!(s, "foo"),
+            _ => assert!(false),
+        }
+
+        // Resolved classes are remembered between GLOBAL and REDUCE
+        match r!(b"\x80\x02cbuiltins\nstr\nq\x00X\x01\x00\x00\x00a\x85Rh\x00X\x01\x00\x00\x00b\x85R\x86.") {
+            Ok(Value::Tuple(t)) => match &t.borrow()[..] {
+                &[Value::Unicode(ref a), Value::Unicode(ref b)] => {assert_eq!(a, "a"); assert_eq!(b, "b")},
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        match r!(b"\x80\x02cgeometry\nPoint\nK\x01K\x02\x86\x81q\x00K\x03K\x04\x86b.") {
+            Ok(Value::Tuple(t)) => match &t.borrow()[..] {
+                &[Value::Int(3), Value::Int(4)] => (),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // The memo holds the built instance
+        match r!(b"\x80\x02cgeometry\nPoint\nK\x01\x85\x81q\x00K\x02\x85bh\x00\x86.") {
+            Ok(Value::Tuple(t)) => match &t.borrow()[..] {
+                &[Value::Tuple(ref a), Value::Tuple(ref b)] => {
+                    assert_eq!(&a.borrow()[..], &[Value::Int(2)]);
+                    assert_eq!(&b.borrow()[..], &[Value::Int(2)]);
+                },
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Unknown classes are decoded as usual
+        match r!(b"\x80\x02cgeometry\nLine\n)\x81K\x01b.") {
+            Ok(Value::Object(obj)) => assert!(obj.borrow().state.is_some()),
+            _ => assert!(false),
+        }
+
+        match r!(b"\x80\x02cbuiltins\nstr\nX\x01\x00\x00\x00a\x85R)b.") {
+            Err(Error::UnsupportedState) => (),
+            _ => assert!(false),
+        }
+
+        // Each name is looked up once, known or not
+        let calls = Rc::new(Cell::new(0));
+        let mut machine = Machine::new();
+        let counter = calls.clone();
+        machine.set_class_resolver(move |module: &str, name: &str| {
+            counter.set(counter.get() + 1);
+            resolve(module, name)
+        });
+        let buffer = b"\x80\x02cgeometry\nLine\ncgeometry\nLine\ncgeometry\nPoint\ncgeometry\nPoint\n\x86\x86\x86.";
+        machine.load(&mut Cursor::new(&buffer[..])).unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_persistent_id() {
+        fn load(pid: Value) -> Result<Value, Error> {
+            match pid {
+                Value::Unicode(ref pid) if pid == "record" => Ok(Value::Int(1)),
+                Value::Tuple(_) => Ok(Value::Int(2)),
+                _ => Err(Error::InvalidValueOnStack),
+            }
+        }
+
+        macro_rules! p {
+            ($buffer: expr) => ({
+                let mut machine = Machine::new();
+                machine.set_persistent_loader(load);
+                machine.load(&mut Cursor::new(&$buffer[..]))
+            })
+        }
+
+        match p!(b"Precord\n.") {
+            Ok(Value::Int(1)) => (),
+            _ => assert!(false),
+        }
+        match p!(b"\x80\x02X\x07\x00\x00\x00storage\x85Q.") {
+            Ok(Value::Int(2)) => (),
+            _ => assert!(false),
+        }
+        match p!(b"Punknown\n.") {
+            Err(Error::InvalidValueOnStack) => (),
+            _ => assert!(false),
+        }
+        e!(b"Precord\n.", Error::UnsupportedPersistentId);
+        e!(b"Q.", Error::EmptyStack);
+    }
+
+    #[test]
+    fn test_extension() {
+        let mut registry = ExtensionRegistry::new();
+        registry.add_extension("module", "name", 1).unwrap();
+        registry.add_extension("module", "other", 0x1234).unwrap();
+        registry.add_extension("other", "name", 0x12345678).unwrap();
+
+        macro_rules! x {
+            ($buffer: expr) => ({
+                let mut machine = Machine::new();
+                machine.set_extension_registry(registry.clone());
+                machine.load(&mut Cursor::new(&$buffer[..]))
+            })
+        }
+
+        match x!(b"\x80\x02\x82\x01.") {
+            Ok(v) => global!(v, "module", "name"),
+            _ => assert!(false),
+        }
+        match x!(b"\x80\x02\x83\x34\x12.") {
+            Ok(v) => global!(v, "module", "other"),
+            _ => assert!(false),
+        }
+        match x!(b"\x80\x02\x84\x78\x56\x34\x12.") {
+            Ok(v) => global!(v, "other", "name"),
+            _ => assert!(false),
+        }
+        match x!(b"\x80\x02\x82\x02.") {
+            Err(Error::UnregisteredExtension(2)) => (),
+            _ => assert!(false),
+        }
+        match x!(b"\x80\x02\x84\xff\xff\xff\xff.") {
+            Err(Error::InvalidExtensionCode(-1)) => (),
+            _ => assert!(false),
+        }
+        e!(b"\x80\x02\x82\x01.", Error::UnregisteredExtension(1));
+    }
+
+    #[test]
+    fn test_unpickle_graph() {
+        // l = []; l.append(l)
+        let graph = unpickle_graph(&mut Cursor::new(&b"\x80\x02]q\x00h\x00a."[..])).unwrap();
+        assert_eq!(graph.len(), 1);
+        assert!(unpickle_graph(&mut Cursor::new(&b"\x80\x02]q\x00h\x00a"[..])).is_err());
+
+        // A cycle left only in the memo is freed too
+        let mut machine = Machine::new();
+        let mut rd = Cursor::new(&b"\x80\x02]q\x00h\x00a0N."[..]);
+        for _ in 0..5 {
+            assert!(!machine.execute(&mut rd).unwrap());
+        }
+        let weak = match machine.memo.get(0) {
+            Some(&Value::List(ref l)) => Rc::downgrade(l),
+            _ => panic!(),
+        };
+        let graph = machine.load_graph(&mut rd).unwrap();
+        assert_eq!(graph[graph.root()], Node::None);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_deep() {
+        let depth = 100000;
+
+        // [[[...]]]
+        let mut buffer = b"\x80\x02".to_vec();
+        buffer.extend(vec![b']'; depth]);
+        buffer.extend(vec![b'a'; depth - 1]);
+        buffer.push(b'.');
+        let a = unpickle(&mut Cursor::new(&buffer[..])).unwrap();
+        let b = unpickle(&mut Cursor::new(&buffer[..])).unwrap();
+        assert!(a == b);
+        assert!(format!("{}", a).starts_with("[[["));
+        assert!(format!("{:?}", a).starts_with("List([List(["));
+        drop(a);
+        assert_eq!(ValueGraph::from_value(b).len(), depth);
+
+        // (((),),)
+        let mut buffer = b"\x80\x02)".to_vec();
+        buffer.extend(vec![b'\x85'; depth]);
+        buffer.push(b'.');
+        let a = unpickle(&mut Cursor::new(&buffer[..])).unwrap();
+        let b = unpickle(&mut Cursor::new(&buffer[..])).unwrap();
+        assert!(a.is_hashable());
+        let mut set = Set::new();
+        set.insert(a);
+        assert!(set.contains(&b));
+    }
+
     // Errors
 
     #[test]
@@ -530,10 +1629,9 @@ mod tests {
         e!(b"L0.1\n.", Error::InvalidLong);
         e!(b"La\n.", Error::InvalidLong);
         e!(b"L\n\n.", Error::InvalidLong);
-        // LONG1
-        e!(b"\x8a\x00.", Error::InvalidLong);
         // LONG4
-        e!(b"\x8b\x00\x00\x00\x00.", Error::InvalidLong);
+        e!(b"\x80\x02\x8b\xff\xff\xff\xff.", Error::NegativeLength);
+        e!(b"\x80\x02\x8b\xff\xff\xff\x7f.", Error::Io(_));
     }
 
     #[test]
@@ -560,8 +1658,6 @@ mod tests {
 
     #[test]
     fn test_unicode_error() {
-        // UNICODE
-        e!(b"V\xe2\x28\xa1\n", Error::UnicodeError);
         // BINUNICODE
         e!(b"X\x03\x00\x00\x00\xe2\x28\xa1", Error::UnicodeError);
     }
@@ -570,6 +1666,7 @@ mod tests {
     fn test_invalid_proto() {
         e!(b"\x80\x00", Error::InvalidProto(0));
         e!(b"\x80\x01", Error::InvalidProto(1));
+        e!(b"\x80\x06", Error::InvalidProto(6));
         e!(b"\x80\x64", Error::InvalidProto(100));
     }
 }
@@ -0,0 +1,108 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::{HashMap};
+use std::mem::{replace};
+
+// Indices at most this far past the end of the vector still extend it.
+const MAX_GAP: usize = 1024;
+
+/// Values stored by PUT, BINPUT, LONG_BINPUT and MEMOIZE.
+///
+/// Picklers number them sequentially, so they are kept in a vector indexed
+/// by their number. Numbers far past its end, which protocol 0 PUT may
+/// write, are kept in a map instead.
+pub struct Memo<T> {
+    dense: Vec<Option<T>>,
+    sparse: HashMap<usize, T>,
+    len: usize,
+}
+
+impl<T> Memo<T> {
+    pub fn new() -> Self {
+        Memo {
+            dense: Vec::new(),
+            sparse: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of stored values.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        match self.dense.get(i) {
+            Some(&Some(ref value)) => Some(value),
+            // May have been stored before the vector grew this far
+            _ => self.sparse.get(&i),
+        }
+    }
+
+    pub fn insert(&mut self, i: usize, value: T) {
+        if i >= self.dense.len() && i - self.dense.len() <= MAX_GAP {
+            while self.dense.len() <= i {
+                self.dense.push(None);
+            }
+        }
+
+        if i < self.dense.len() {
+            let old = replace(&mut self.dense[i], Some(value));
+            if old.is_none() && self.sparse.remove(&i).is_none() {
+                self.len += 1;
+            }
+        } else if self.sparse.insert(i, value).is_none() {
+            self.len += 1;
+        }
+    }
+
+    /// Moves the stored values into `values`.
+    pub fn take_values(&mut self, values: &mut Vec<T>) {
+        for value in self.dense.drain(..) {
+            values.extend(value);
+        }
+        values.extend(self.sparse.drain().map(|(_, value)| value));
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Memo, MAX_GAP};
+
+    #[test]
+    fn test_memo() {
+        let mut memo = Memo::new();
+        memo.insert(0, "a");
+        memo.insert(1, "b");
+        memo.insert(0, "c");
+        assert_eq!(memo.len(), 2);
+        assert_eq!(memo.get(0), Some(&"c"));
+        assert_eq!(memo.get(2), None);
+
+        memo.insert(1000000, "d");
+        assert_eq!(memo.len(), 3);
+        assert_eq!(memo.get(1000000), Some(&"d"));
+
+        // Stored in the map, then the vector grows past it
+        let far = 2 + MAX_GAP + 1;
+        memo.insert(far, "e");
+        memo.insert(far - 1, "f");
+        assert_eq!(memo.get(far), Some(&"e"));
+        memo.insert(far, "g");
+        assert_eq!(memo.get(far), Some(&"g"));
+        assert_eq!(memo.len(), 5);
+
+        let mut values = Vec::new();
+        memo.take_values(&mut values);
+        values.sort();
+        assert_eq!(values, vec!["b", "c", "d", "f", "g"]);
+        assert_eq!(memo.len(), 0);
+    }
+}
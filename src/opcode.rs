@@ -44,6 +44,7 @@ quick_error! {
 
         InvalidProto(proto: u8)
         NegativeLength
+        LengthTooLarge
     }
 }
 
@@ -56,6 +57,7 @@ pub enum BooleanOrInt {
 #[derive(Debug)]
 pub enum OpCode {
     Proto(u8),
+    Frame(u64),
     Stop,
 
     Int(BooleanOrInt),
@@ -77,6 +79,16 @@ pub enum OpCode {
 
     Unicode(String),
     BinUnicode(String),
+    ShortBinUnicode(String),
+    BinUnicode8(String),
+
+    BinBytes(Vec<u8>),
+    ShortBinBytes(Vec<u8>),
+    BinBytes8(Vec<u8>),
+
+    ByteArray8(Vec<u8>),
+    NextBuffer,
+    ReadonlyBuffer,
 
     Float(f64),
     BinFloat(f64),
@@ -97,6 +109,10 @@ pub enum OpCode {
     SetItem,
     SetItems,
 
+    EmptySet,
+    AddItems,
+    FrozenSet,
+
     Pop,
     Dup,
     Mark,
@@ -108,17 +124,20 @@ pub enum OpCode {
     Put(usize),
     BinPut(usize),
     LongBinPut(usize),
+    Memoize,
 
     Ext1(u8),
     Ext2(u16),
     Ext4(i32),
 
     Global(Vec<u8>, Vec<u8>),
+    StackGlobal,
     Reduce,
     Build,
     Inst(Vec<u8>, Vec<u8>),
     Obj,
     NewObj,
+    NewObjEx,
     PersId(Vec<u8>),
     BinPersId,
 }
@@ -203,6 +222,14 @@ pub fn read_opcode<R>(rd: &mut R) -> Result<OpCode, Error> where R: Read + BufRe
         })
     }
 
+    macro_rules! ensure_fits_usize {
+        ($n: expr) => ({
+            if $n as usize as u64 != $n {
+                return Err(Error::LengthTooLarge)
+            }
+        })
+    }
+
     let marker = try!(rd.read_u8());
     return Ok(match marker {
         b'\x80' => {
@@ -212,6 +239,11 @@ pub fn read_opcode<R>(rd: &mut R) -> Result<OpCode, Error> where R: Read + BufRe
             }
             OpCode::Proto(version)
         }
+        b'\x95' => {
+            let length = try!(rd.read_u64::<LittleEndian>());
+            ensure_fits_usize!(length);
+            OpCode::Frame(length)
+        }
         b'.' => OpCode::Stop,
         b'I' => OpCode::Int(try!(read_decimal_int(rd))),
         b'J' => OpCode::BinInt(try!(rd.read_i32::<LittleEndian>())),
@@ -260,6 +292,49 @@ pub fn read_opcode<R>(rd: &mut R) -> Result<OpCode, Error> where R: Read + BufRe
             try!(read_exact(rd, buf.as_mut()));
             OpCode::BinUnicode(try!(String::from_utf8(buf)))
         },
+        b'\x8c' => {
+            let length = try!(rd.read_u8());
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, buf.as_mut()));
+            OpCode::ShortBinUnicode(try!(String::from_utf8(buf)))
+        },
+        b'\x8d' => {
+            let length = try!(rd.read_u64::<LittleEndian>());
+            ensure_fits_usize!(length);
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, buf.as_mut()));
+            OpCode::BinUnicode8(try!(String::from_utf8(buf)))
+        },
+
+        b'B' => {
+            let length = try!(rd.read_u32::<LittleEndian>());
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, &mut buf));
+            OpCode::BinBytes(buf)
+        }
+        b'C' => {
+            let length = try!(rd.read_u8());
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, &mut buf));
+            OpCode::ShortBinBytes(buf)
+        }
+        b'\x8e' => {
+            let length = try!(rd.read_u64::<LittleEndian>());
+            ensure_fits_usize!(length);
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, &mut buf));
+            OpCode::BinBytes8(buf)
+        }
+
+        b'\x96' => {
+            let length = try!(rd.read_u64::<LittleEndian>());
+            ensure_fits_usize!(length);
+            let mut buf = vec![0; length as usize];
+            try!(read_exact(rd, &mut buf));
+            OpCode::ByteArray8(buf)
+        }
+        b'\x97' => OpCode::NextBuffer,
+        b'\x98' => OpCode::ReadonlyBuffer,
 
         b'F' => {
             let s = try!(read_until_newline(rd));
@@ -285,6 +360,10 @@ pub fn read_opcode<R>(rd: &mut R) -> Result<OpCode, Error> where R: Read + BufRe
         b's' => OpCode::SetItem,
         b'u' => OpCode::SetItems,
 
+        b'\x8f' => OpCode::EmptySet,
+        b'\x90' => OpCode::AddItems,
+        b'\x91' => OpCode::FrozenSet,
+
         b'0' => OpCode::Pop,
         b'2' => OpCode::Dup,
         b'(' => OpCode::Mark,
@@ -320,17 +399,20 @@ pub fn read_opcode<R>(rd: &mut R) -> Result<OpCode, Error> where R: Read + BufRe
             ensure_not_negative!(n);
             OpCode::LongBinPut(n as usize)
         }
+        b'\x94' => OpCode::Memoize,
 
         b'\x82' => OpCode::Ext1(try!(rd.read_u8())),
         b'\x83' => OpCode::Ext2(try!(rd.read_u16::<LittleEndian>())),
         b'\x84' => OpCode::Ext4(try!(rd.read_i32::<LittleEndian>())),  // TODO: ensure_not_negative?
 
         b'c' => OpCode::Global(try!(read_until_newline(rd)), try!(read_until_newline(rd))),
+        b'\x93' => OpCode::StackGlobal,
         b'R' => OpCode::Reduce,
         b'b' => OpCode::Build,
         b'i' => OpCode::Inst(try!(read_until_newline(rd)), try!(read_until_newline(rd))),
         b'o' => OpCode::Obj,
         b'\x81' => OpCode::NewObj,
+        b'\x92' => OpCode::NewObjEx,
         b'P' => OpCode::PersId(try!(read_until_newline(rd))),
         b'Q' => OpCode::BinPersId,
 
@@ -383,6 +465,12 @@ mod tests {
         t!(b"\x80\x0a", OpCode::Proto(n), assert_eq!(n, 10));
     }
 
+    #[test]
+    fn test_frame() {
+        e!(b"\x95\x01\x00", Error::Read(_));
+        t!(b"\x95\x0b\x00\x00\x00\x00\x00\x00\x00", OpCode::Frame(n), assert_eq!(n, 11));
+    }
+
     fn test_stop() {
         t!(b".", OpCode::Stop, ());
     }
@@ -501,6 +589,56 @@ mod tests {
         t!(b"X\t\x00\x00\x00abc\xd0\xb3\xd0\xb4\xd0\xb5q", OpCode::BinUnicode(s), assert_eq!(s, "abcгде"));
     }
 
+    #[test]
+    fn test_short_bin_unicode() {
+        e!(b"\x8c\x02\xd0", Error::Io(_));
+        e!(b"\x8c\x02\xd0\xd0", Error::InvalidString);
+        t!(b"\x8c\x00", OpCode::ShortBinUnicode(s), assert_eq!(s, ""));
+        t!(b"\x8c\x05ab\xd0\xb3c", OpCode::ShortBinUnicode(s), assert_eq!(s, "abгc"));
+    }
+
+    #[test]
+    fn test_bin_unicode8() {
+        e!(b"\x8d\x03\x00\x00\x00", Error::Read(_));
+        t!(b"\x8d\x03\x00\x00\x00\x00\x00\x00\x00abc", OpCode::BinUnicode8(s), assert_eq!(s, "abc"));
+    }
+
+    #[test]
+    fn test_bin_bytes() {
+        e!(b"B\x03\x00", Error::Read(_));
+        t!(b"B\x00\x00\x00\x00", OpCode::BinBytes(s), assert_eq!(s, b""));
+        t!(b"B\x03\x00\x00\x00abc", OpCode::BinBytes(s), assert_eq!(s, b"abc"));
+    }
+
+    #[test]
+    fn test_short_bin_bytes() {
+        e!(b"C", Error::Read(_));
+        t!(b"C\x00", OpCode::ShortBinBytes(s), assert_eq!(s, b""));
+        t!(b"C\x03abc", OpCode::ShortBinBytes(s), assert_eq!(s, b"abc"));
+    }
+
+    #[test]
+    fn test_bin_bytes8() {
+        e!(b"\x8e\x03\x00\x00\x00", Error::Read(_));
+        t!(b"\x8e\x03\x00\x00\x00\x00\x00\x00\x00abc", OpCode::BinBytes8(s), assert_eq!(s, b"abc"));
+    }
+
+    #[test]
+    fn test_byte_array8() {
+        e!(b"\x96\x03\x00\x00\x00", Error::Read(_));
+        t!(b"\x96\x03\x00\x00\x00\x00\x00\x00\x00abc", OpCode::ByteArray8(s), assert_eq!(s, b"abc"));
+    }
+
+    #[test]
+    fn test_next_buffer() {
+        t!(b"\x97", OpCode::NextBuffer, ());
+    }
+
+    #[test]
+    fn test_readonly_buffer() {
+        t!(b"\x98", OpCode::ReadonlyBuffer, ());
+    }
+
     #[test]
     fn test_float() {
         e!(b"F", Error::InvalidString);
@@ -587,6 +725,21 @@ mod tests {
         t!(b"u", OpCode::SetItems, ());
     }
 
+    #[test]
+    fn test_empty_set() {
+        t!(b"\x8f", OpCode::EmptySet, ());
+    }
+
+    #[test]
+    fn test_add_items() {
+        t!(b"\x90", OpCode::AddItems, ());
+    }
+
+    #[test]
+    fn test_frozen_set() {
+        t!(b"\x91", OpCode::FrozenSet, ());
+    }
+
     #[test]
     fn test_pop() {
         t!(b"0", OpCode::Pop, ());
@@ -656,6 +809,11 @@ mod tests {
         t!(b"r\x0a\x00\x00\x01", OpCode::LongBinPut(n), assert_eq!(n, 16777226));
     }
 
+    #[test]
+    fn test_memoize() {
+        t!(b"\x94", OpCode::Memoize, ());
+    }
+
     #[test]
     fn test_ext1() {
         e!(b"\x82", Error::Read(_));
@@ -686,6 +844,11 @@ mod tests {
         t!(b"cmodule\nclass\n", OpCode::Global(a, b), {assert_eq!(a, b"module"); assert_eq!(b, b"class");});
     }
 
+    #[test]
+    fn test_stack_global() {
+        t!(b"\x93", OpCode::StackGlobal, ())
+    }
+
     #[test]
     fn test_reduce() {
         t!("R", OpCode::Reduce, ())
@@ -714,6 +877,11 @@ mod tests {
         t!(b"\x81", OpCode::NewObj, ())
     }
 
+    #[test]
+    fn test_new_obj_ex() {
+        t!(b"\x92", OpCode::NewObjEx, ())
+    }
+
     #[test]
     fn test_persid() {
         e!(b"P", Error::InvalidString);
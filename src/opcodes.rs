@@ -59,3 +59,24 @@ pub const TUPLE2: u8 = b'\x86';
 pub const TUPLE3: u8 = b'\x87';
 pub const NEWTRUE: u8 = b'\x88';
 pub const NEWFALSE: u8 = b'\x89';
+
+// Protocol 3
+pub const BINBYTES: u8 = b'B';
+pub const SHORT_BINBYTES: u8 = b'C';
+
+// Protocol 4
+pub const SHORT_BINUNICODE: u8 = b'\x8c';
+pub const BINUNICODE8: u8 = b'\x8d';
+pub const BINBYTES8: u8 = b'\x8e';
+pub const EMPTY_SET: u8 = b'\x8f';
+pub const ADDITEMS: u8 = b'\x90';
+pub const FROZENSET: u8 = b'\x91';
+pub const NEWOBJ_EX: u8 = b'\x92';
+pub const STACK_GLOBAL: u8 = b'\x93';
+pub const MEMOIZE: u8 = b'\x94';
+pub const FRAME: u8 = b'\x95';
+
+// Protocol 5
+pub const BYTEARRAY8: u8 = b'\x96';
+pub const NEXT_BUFFER: u8 = b'\x97';
+pub const READONLY_BUFFER: u8 = b'\x98';
@@ -0,0 +1,24 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use machine::{Error};
+use value::{Value};
+
+/// Loads objects referenced by PERSID and BINPERSID, like
+/// `Unpickler.persistent_load` does.
+pub trait PersistentLoader {
+    /// Returns the object with persistent id `pid`. PERSID ids are passed as
+    /// `Value::Unicode`, BINPERSID ids may be any value.
+    fn persistent_load<'a>(&self, pid: Value<'a>) -> Result<Value<'a>, Error>;
+}
+
+impl<F> PersistentLoader for F where F: for<'a> Fn(Value<'a>) -> Result<Value<'a>, Error> {
+    fn persistent_load<'a>(&self, pid: Value<'a>) -> Result<Value<'a>, Error> {
+        self(pid)
+    }
+}
@@ -0,0 +1,1368 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serialization of values, the reverse of `machine`.
+
+use std::io::{Write, Error as IoError};
+use std::collections::{HashMap};
+use std::hash::{Hasher, BuildHasherDefault};
+use std::mem::{discriminant, Discriminant};
+use std::cmp::{min};
+
+use num::bigint::{BigInt, Sign};
+use byteorder::{ByteOrder, LittleEndian, BigEndian};
+
+use value::{Value, Text, Shared, Object, Construction, Set, Dict};
+
+use opcodes::*;
+
+/// Highest protocol a `Pickler` writes.
+pub const HIGHEST_PROTOCOL: u8 = 5;
+
+// Items added by one APPENDS, SETITEMS or ADDITEMS, as in CPython.
+const BATCH_SIZE: usize = 1000;
+
+// A frame is written once it's this long, and strings this long are
+// written outside frames, as in CPython.
+const FRAME_SIZE_TARGET: usize = 64 * 1024;
+
+// Shorter frames aren't worth their header.
+const FRAME_SIZE_MIN: usize = 4;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            from()
+        }
+        InvalidProto(protocol: u8)
+        // A container contains itself
+        RecursiveValue
+        // A string or bytes longer than the protocol allows
+        TooLarge
+        // An object whose construction needs a tuple of arguments
+        InvalidArgs
+        // A `PickleWriter` list ended without being begun, a second value
+        // written outside lists, or a pickle finished with lists left open
+        // or without a value
+        Unbalanced
+    }
+}
+
+// Address of a container.
+fn address(value: &Value) -> Option<usize> {
+    match *value {
+        Value::List(ref items) | Value::Tuple(ref items) => Some(items.as_ptr() as usize),
+        Value::Dict(ref dict) => Some(dict.as_ptr() as usize),
+        Value::Set(ref set) | Value::FrozenSet(ref set) => Some(set.as_ptr() as usize),
+        Value::Object(ref obj) => Some(obj.as_ptr() as usize),
+        _ => None,
+    }
+}
+
+// Address, type and length of a string or a container. Values with the
+// same identity are the same.
+fn identity<'a>(value: &Value<'a>) -> Option<(usize, Discriminant<Value<'a>>, usize)> {
+    let (ptr, len) = match *value {
+        Value::String(ref s) | Value::Bytes(ref s) => (s.as_ptr() as usize, s.len()),
+        Value::Unicode(ref s) => (s.as_ptr() as usize, s.len()),
+        _ => match address(value) {
+            Some(ptr) => (ptr, 0),
+            None => return None,
+        },
+    };
+    Some((ptr, discriminant(value), len))
+}
+
+// Hashes addresses by a multiplication, SipHash makes the memo several times
+// slower.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(self.0 << 8 | b as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        let n = n.wrapping_mul(0x9e3779b97f4a7c15);
+        self.0 = n ^ n >> 32;
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64)
+    }
+}
+
+type Addresses<T> = HashMap<usize, T, BuildHasherDefault<AddressHasher>>;
+
+#[derive(Default)]
+struct Memo<'a> {
+    len: usize,
+    // Indices of strings and containers by their address
+    ids: Addresses<(usize, Discriminant<Value<'a>>, usize)>,
+    globals: HashMap<(String, String), usize>,
+    // Module and class names of STACK_GLOBAL
+    names: HashMap<String, usize>,
+    // Memoized values are kept so that their addresses aren't reused
+    values: Vec<Value<'a>>,
+    // Containers being written, with the memo length when they were entered
+    path: Addresses<usize>,
+}
+
+impl<'a> Memo<'a> {
+    fn get(&self, value: &Value<'a>) -> Option<usize> {
+        if let Value::Global { ref module, ref name } = *value {
+            return self.globals.get(&(module.clone(), name.clone())).cloned()
+        }
+        match (identity(value), identity(value).and_then(|(ptr, _, _)| self.ids.get(&ptr))) {
+            (Some((_, kind, len)), Some(&(i, k, l))) if kind == k && len == l => Some(i),
+            _ => None,
+        }
+    }
+
+    // Returns the index of the value. Bytearrays are held by value and have
+    // no identity to be found by, but take an index like in CPython.
+    fn insert(&mut self, value: &Value<'a>) -> usize {
+        let i = self.len;
+        if let Value::Global { ref module, ref name } = *value {
+            self.globals.insert((module.clone(), name.clone()), i);
+        } else if let Some((ptr, kind, len)) = identity(value) {
+            self.ids.insert(ptr, (i, kind, len));
+            self.values.push(value.clone());
+        }
+        self.len += 1;
+        i
+    }
+
+    fn insert_name(&mut self, name: String) -> usize {
+        let i = self.len;
+        self.names.insert(name, i);
+        self.len += 1;
+        i
+    }
+}
+
+// Containers whose items are written in batches.
+enum Items<'a> {
+    Values(Shared<'a, Vec<Value<'a>>>),
+    Dict(Shared<'a, Dict<'a>>),
+    Set(Shared<'a, Set<'a>>),
+    ObjectList(Shared<'a, Object<'a>>),
+    ObjectDict(Shared<'a, Object<'a>>),
+}
+
+impl<'a> Items<'a> {
+    // Number of items, or of keys and values.
+    fn len(&self) -> usize {
+        match *self {
+            Items::Values(ref items) => items.borrow().len(),
+            Items::Dict(ref dict) => dict.borrow().len(),
+            Items::Set(ref set) => set.borrow().len(),
+            Items::ObjectList(ref obj) => obj.borrow().list_items.len(),
+            Items::ObjectDict(ref obj) => obj.borrow().dict_items.len(),
+        }
+    }
+
+    // Pushes the item, or the key and the value, at `i`.
+    fn push(&self, i: usize, tasks: &mut Vec<Task<'a>>) {
+        match *self {
+            Items::Values(ref items) => tasks.push(Task::Value(items.borrow()[i].clone())),
+            Items::Set(ref set) => tasks.push(Task::Value(set.borrow().iter().as_slice()[i].clone())),
+            Items::ObjectList(ref obj) => tasks.push(Task::Value(obj.borrow().list_items[i].clone())),
+            Items::Dict(ref dict) => {
+                let dict = dict.borrow();
+                let (ref key, ref value) = dict.iter().as_slice()[i];
+                tasks.push(Task::Value(key.clone()));
+                tasks.push(Task::Value(value.clone()));
+            },
+            Items::ObjectDict(ref obj) => {
+                let obj = obj.borrow();
+                let (ref key, ref value) = obj.dict_items[i];
+                tasks.push(Task::Value(key.clone()));
+                tasks.push(Task::Value(value.clone()));
+            },
+        }
+    }
+}
+
+// How the items of a batch are added to their container.
+#[derive(Clone, Copy)]
+enum Add {
+    // They stay on the stack, e.g. between MARK and TUPLE
+    None,
+    // By an opcode after each of them, in protocol 0
+    Each(u8),
+    // By MARK ... `many`, or by `one` after a single item
+    Batch(u8, Option<u8>),
+}
+
+enum Task<'a> {
+    Value(Value<'a>),
+    Opcode(u8),
+    Line(u8, String, String),
+    Name(String),
+    // Items from the index on
+    Batch(Items<'a>, usize, Add),
+    // The opcode builds the value from the items on the stack, unless a
+    // cycle through them has memoized it, then they're popped by the
+    // opcodes and it's taken from the memo
+    Build(Value<'a>, u8, Vec<u8>),
+    // Memoizes the value on the stack, or replaces it by the memoized one
+    Memoize(Value<'a>),
+    // End of a container, and the memo length when it was entered before
+    Leave(usize, Option<usize>),
+}
+
+/// Writes values as pickles of the given protocol, choosing the shortest
+/// opcodes the protocol has, like Python's `pickle.dump`.
+///
+/// Values which older protocols have no opcodes for are written as calls,
+/// like CPython does: bytes as `_codecs.encode`, bytearrays and sets as
+/// calls of their type.
+///
+/// Strings and containers are memoized, so containers which are the same
+/// `Rc` are the same object when Python loads the pickle, and containers
+/// may contain themselves. The memo is cleared by each `dump`.
+///
+/// Protocol 4 and newer pickles are grouped into frames of about 64 KiB,
+/// with long strings and bytes between them, like CPython writes them.
+pub struct Pickler<W: Write> {
+    wr: W,
+    protocol: u8,
+    fast: bool,
+    // The frame being written, if the protocol has frames
+    frame: Option<Vec<u8>>,
+}
+
+impl<W: Write> Pickler<W> {
+    pub fn new(wr: W, protocol: u8) -> Result<Self, Error> {
+        if protocol > HIGHEST_PROTOCOL {
+            return Err(Error::InvalidProto(protocol))
+        }
+        Ok(Pickler {
+            wr: wr,
+            protocol: protocol,
+            fast: false,
+            frame: if protocol >= 4 { Some(Vec::new()) } else { None },
+        })
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// Disables the memo, like `Pickler.fast` in Python. Pickles are
+    /// shorter and written faster, but shared containers are written once
+    /// for each reference and recursive ones fail with `RecursiveValue`.
+    pub fn set_fast(&mut self, fast: bool) {
+        self.fast = fast;
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.wr
+    }
+
+    pub fn into_inner(self) -> W {
+        self.wr
+    }
+
+    /// Writes a whole pickle of `value`, ending with STOP.
+    pub fn dump<'a>(&mut self, value: &Value<'a>) -> Result<(), Error> {
+        // What's left of a failed dump is dropped, PROTO precedes the frames
+        if let Some(ref mut frame) = self.frame {
+            frame.clear();
+        }
+        try!(self.write_proto());
+        try!(self.save_all(value));
+        try!(self.write(&[STOP]));
+        self.commit_frame(true)
+    }
+
+    fn write_proto(&mut self) -> Result<(), Error> {
+        if self.protocol >= 2 {
+            try!(self.wr.write_all(&[PROTO, self.protocol]));
+        }
+        Ok(())
+    }
+
+    // Writes the value and everything in it.
+    fn save_all<'a>(&mut self, value: &Value<'a>) -> Result<(), Error> {
+        let mut stack = vec![Task::Value(value.clone())];
+        let mut tasks = Vec::new();
+        let mut memo = Memo::default();
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Value(value) => try!(self.save(value, &mut tasks, &mut memo)),
+                Task::Opcode(opcode) => try!(self.write(&[opcode])),
+                Task::Line(opcode, module, name) => try!(self.write_line(opcode, &module, &name)),
+                Task::Name(name) => try!(self.save_name(name, &mut memo)),
+                Task::Batch(items, start, add) => self.batch(items, start, add, &mut tasks),
+                Task::Build(value, opcode, pops) => match self.memoized(&value, &memo) {
+                    Some(i) => {
+                        try!(self.write(&pops));
+                        try!(self.get(i))
+                    },
+                    None => {
+                        try!(self.write(&[opcode]));
+                        try!(self.memoize(&value, &mut memo))
+                    },
+                },
+                Task::Memoize(value) => match self.memoized(&value, &memo) {
+                    Some(i) => {
+                        try!(self.write(&[POP]));
+                        try!(self.get(i))
+                    },
+                    None => try!(self.memoize(&value, &mut memo)),
+                },
+                Task::Leave(ptr, previous) => match previous {
+                    Some(len) => {
+                        memo.path.insert(ptr, len);
+                    },
+                    None => {
+                        memo.path.remove(&ptr);
+                    },
+                },
+            }
+            stack.extend(tasks.drain(..).rev());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match self.frame {
+            Some(ref mut frame) => {
+                frame.extend_from_slice(buf);
+                Ok(())
+            },
+            None => self.wr.write_all(buf).map_err(Error::from),
+        }
+    }
+
+    // Writes the frame once it's long enough, or when forced, the frames
+    // end only between values.
+    fn commit_frame(&mut self, force: bool) -> Result<(), Error> {
+        let frame = match self.frame {
+            Some(ref mut frame) if force || frame.len() >= FRAME_SIZE_TARGET => frame,
+            _ => return Ok(()),
+        };
+        if frame.len() >= FRAME_SIZE_MIN {
+            let mut buf = [FRAME, 0, 0, 0, 0, 0, 0, 0, 0];
+            LittleEndian::write_u64(&mut buf[1..], frame.len() as u64);
+            try!(self.wr.write_all(&buf));
+        }
+        try!(self.wr.write_all(frame));
+        frame.clear();
+        Ok(())
+    }
+
+    fn write_line(&mut self, opcode: u8, module: &str, name: &str) -> Result<(), Error> {
+        try!(self.write(&[opcode]));
+        try!(self.write(module.as_bytes()));
+        try!(self.write(b"\n"));
+        try!(self.write(name.as_bytes()));
+        self.write(b"\n")
+    }
+
+    fn write_len(&mut self, len: usize, short: Option<u8>, long: Option<u8>, long8: Option<u8>) -> Result<(), Error> {
+        let (buf, size) = try!(len_header(len, short, long, long8));
+        self.write(&buf[..size])
+    }
+
+    // Writes the opcode, the length and the bytes, long ones outside frames
+    // so that they aren't copied into them.
+    fn write_bytes(&mut self, s: &[u8], short: Option<u8>, long: Option<u8>, long8: Option<u8>) -> Result<(), Error> {
+        if self.frame.is_none() || s.len() < FRAME_SIZE_TARGET {
+            try!(self.write_len(s.len(), short, long, long8));
+            return self.write(s)
+        }
+        let (buf, size) = try!(len_header(s.len(), short, long, long8));
+        try!(self.commit_frame(true));
+        try!(self.wr.write_all(&buf[..size]));
+        self.wr.write_all(s).map_err(Error::from)
+    }
+
+    fn write_unicode(&mut self, s: &str) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            let short = if self.protocol >= 4 { Some(SHORT_BINUNICODE) } else { None };
+            let long8 = if self.protocol >= 4 { Some(BINUNICODE8) } else { None };
+            self.write_bytes(s.as_bytes(), short, Some(BINUNICODE), long8)
+        } else {
+            try!(self.write(&[UNICODE]));
+            try!(self.write(&raw_unicode_escape(s)));
+            self.write(b"\n")
+        }
+    }
+
+    // Writes GET, PUT and their binary forms.
+    fn write_index(&mut self, i: usize, text: u8, short: u8, long: u8) -> Result<(), Error> {
+        if self.protocol == 0 {
+            return self.write(format!("{}{}\n", text as char, i).as_bytes())
+        }
+        if i > 0xffffffff {
+            return Err(Error::TooLarge)
+        }
+        self.write_len(i, Some(short), Some(long), None)
+    }
+
+    fn get(&mut self, i: usize) -> Result<(), Error> {
+        self.write_index(i, GET, BINGET, LONG_BINGET)
+    }
+
+    fn put(&mut self, i: usize) -> Result<(), Error> {
+        if self.protocol >= 4 {
+            self.write(&[MEMOIZE])
+        } else {
+            self.write_index(i, PUT, BINPUT, LONG_BINPUT)
+        }
+    }
+
+    fn memoized<'a>(&self, value: &Value<'a>, memo: &Memo<'a>) -> Option<usize> {
+        if self.fast {
+            return None
+        }
+        memo.get(value)
+    }
+
+    fn memoize<'a>(&mut self, value: &Value<'a>, memo: &mut Memo<'a>) -> Result<(), Error> {
+        if self.fast {
+            return Ok(())
+        }
+        let i = memo.insert(value);
+        self.put(i)
+    }
+
+    fn save_name<'a>(&mut self, name: String, memo: &mut Memo<'a>) -> Result<(), Error> {
+        try!(self.commit_frame(false));
+        if let Some(&i) = memo.names.get(&name) {
+            return self.get(i)
+        }
+        try!(self.write_unicode(&name));
+        if self.fast {
+            return Ok(())
+        }
+        let i = memo.insert_name(name);
+        self.put(i)
+    }
+
+    // Writes a scalar value, or the start of a container and tasks for the
+    // rest of it.
+    fn save<'a>(&mut self, value: Value<'a>, tasks: &mut Vec<Task<'a>>, memo: &mut Memo<'a>) -> Result<(), Error> {
+        try!(self.commit_frame(false));
+        if let Some(i) = self.memoized(&value, memo) {
+            return self.get(i)
+        }
+
+        // A container is entered again only after a cycle through it, which
+        // ends once a container of the cycle is memoized
+        let ptr = address(&value);
+        let entered = match ptr {
+            Some(ptr) => {
+                if memo.path.get(&ptr) == Some(&memo.len) {
+                    return Err(Error::RecursiveValue)
+                }
+                Some(memo.path.insert(ptr, memo.len))
+            },
+            None => None,
+        };
+
+        let bin = self.protocol >= 1;
+        match value {
+            Value::None => try!(self.write(&[NONE])),
+            Value::Bool(b) => match (self.protocol >= 2, b) {
+                (true, true) => try!(self.write(&[NEWTRUE])),
+                (true, false) => try!(self.write(&[NEWFALSE])),
+                (false, true) => try!(self.write(b"I01\n")),
+                (false, false) => try!(self.write(b"I00\n")),
+            },
+            Value::Int(_) | Value::Long(_) => try!(self.save_int(&value)),
+            Value::Float(x) => if bin {
+                let mut buf = [BINFLOAT, 0, 0, 0, 0, 0, 0, 0, 0];
+                BigEndian::write_f64(&mut buf[1..], x);
+                try!(self.write(&buf))
+            } else {
+                try!(self.write(format!("F{}\n", value).as_bytes()))
+            },
+
+            Value::String(ref s) => {
+                if bin {
+                    if s.len() > 0x7fffffff {
+                        return Err(Error::TooLarge)
+                    }
+                    try!(self.write_bytes(s, Some(SHORT_BINSTRING), Some(BINSTRING), None))
+                } else {
+                    try!(self.write(format!("S{}\n", value).as_bytes()))
+                }
+                try!(self.memoize(&value, memo))
+            },
+            Value::Unicode(ref s) => {
+                try!(self.write_unicode(s));
+                try!(self.memoize(&value, memo))
+            },
+            Value::Bytes(ref s) if self.protocol >= 3 => {
+                let long8 = if self.protocol >= 4 { Some(BINBYTES8) } else { None };
+                try!(self.write_bytes(s, Some(SHORT_BINBYTES), Some(BINBYTES), long8));
+                try!(self.memoize(&value, memo))
+            },
+            Value::Bytes(ref s) => {
+                if s.is_empty() {
+                    tasks.push(Task::Value(self.builtin("bytes")));
+                    tasks.push(Task::Value(tuple(vec![])));
+                } else {
+                    let latin1: String = s.iter().map(|&c| c as char).collect();
+                    tasks.push(Task::Value(global("_codecs", "encode")));
+                    tasks.push(Task::Value(tuple(vec![Value::Unicode(Text::from(latin1)), Value::Unicode(Text::from("latin1"))])));
+                }
+                tasks.push(Task::Opcode(REDUCE));
+                tasks.push(Task::Memoize(value.clone()));
+            },
+            // Memoized for the memo indices to follow CPython's, but never
+            // found again: two references to the same bytearray in Python
+            // are two copies here, and are written as such.
+            Value::ByteArray(ref s) if self.protocol >= 5 => {
+                try!(self.write_bytes(s, None, None, Some(BYTEARRAY8)));
+                try!(self.memoize(&value, memo))
+            },
+            Value::ByteArray(ref s) => {
+                tasks.push(Task::Value(self.builtin("bytearray")));
+                let args = if s.is_empty() { vec![] } else { vec![Value::Bytes(s.clone().into())] };
+                tasks.push(Task::Value(tuple(args)));
+                tasks.push(Task::Opcode(REDUCE));
+                tasks.push(Task::Memoize(value.clone()));
+            },
+
+            Value::List(ref items) => {
+                try!(self.write(if bin { &[EMPTY_LIST][..] } else { &[MARK, LIST][..] }));
+                try!(self.memoize(&value, memo));
+                tasks.push(Task::Batch(Items::Values(items.clone()), 0, self.add(APPENDS, APPEND)));
+            },
+            Value::Tuple(ref items) => {
+                let len = items.borrow().len();
+                match len {
+                    0 if bin => try!(self.write(&[EMPTY_TUPLE])),
+                    0 => try!(self.write(&[MARK, TUPLE])),
+                    1 ... 3 if self.protocol >= 2 => {
+                        tasks.push(Task::Batch(Items::Values(items.clone()), 0, Add::None));
+                        tasks.push(Task::Build(value.clone(), [TUPLE1, TUPLE2, TUPLE3][len - 1], vec![POP; len]));
+                    },
+                    _ => {
+                        try!(self.write(&[MARK]));
+                        tasks.push(Task::Batch(Items::Values(items.clone()), 0, Add::None));
+                        let pops = if bin { vec![POP_MARK] } else { vec![POP; len + 1] };
+                        tasks.push(Task::Build(value.clone(), TUPLE, pops));
+                    },
+                }
+            },
+            Value::Dict(ref dict) => {
+                try!(self.write(if bin { &[EMPTY_DICT][..] } else { &[MARK, DICT][..] }));
+                try!(self.memoize(&value, memo));
+                tasks.push(Task::Batch(Items::Dict(dict.clone()), 0, self.add(SETITEMS, SETITEM)));
+            },
+            Value::Set(ref set) if self.protocol >= 4 => {
+                try!(self.write(&[EMPTY_SET]));
+                try!(self.memoize(&value, memo));
+                tasks.push(Task::Batch(Items::Set(set.clone()), 0, Add::Batch(ADDITEMS, None)));
+            },
+            Value::FrozenSet(ref set) if self.protocol >= 4 => {
+                try!(self.write(&[MARK]));
+                tasks.push(Task::Batch(Items::Set(set.clone()), 0, Add::None));
+                tasks.push(Task::Build(value.clone(), FROZENSET, vec![POP_MARK]));
+            },
+            Value::Set(ref set) | Value::FrozenSet(ref set) => {
+                // set(list) or frozenset(list)
+                let name = if let Value::Set(_) = value { "set" } else { "frozenset" };
+                let items = set.borrow().iter().cloned().collect();
+                tasks.push(Task::Value(self.builtin(name)));
+                tasks.push(Task::Value(tuple(vec![Value::List(Shared::new(items))])));
+                tasks.push(Task::Opcode(REDUCE));
+                tasks.push(Task::Memoize(value.clone()));
+            },
+
+            Value::Global { ref module, ref name } => if self.protocol >= 4 {
+                tasks.push(Task::Name(module.clone()));
+                tasks.push(Task::Name(name.clone()));
+                tasks.push(Task::Opcode(STACK_GLOBAL));
+                tasks.push(Task::Memoize(value.clone()));
+            } else {
+                try!(self.write_line(GLOBAL, module, name));
+                try!(self.memoize(&value, memo))
+            },
+            Value::Object(ref obj) => try!(self.save_object(&value, obj, tasks)),
+        }
+
+        if let (Some(ptr), Some(previous)) = (ptr, entered) {
+            tasks.push(Task::Leave(ptr, previous));
+        }
+        Ok(())
+    }
+
+    fn save_int(&mut self, value: &Value) -> Result<(), Error> {
+        let n = value.as_i64();
+        if self.protocol >= 1 {
+            match n {
+                Some(n @ 0 ... 0xff) => return self.write(&[BININT1, n as u8]),
+                Some(n @ 0 ... 0xffff) => {
+                    let mut buf = [BININT2, 0, 0];
+                    LittleEndian::write_u16(&mut buf[1..], n as u16);
+                    return self.write(&buf)
+                },
+                Some(n @ -0x80000000 ... 0x7fffffff) => {
+                    let mut buf = [BININT, 0, 0, 0, 0];
+                    LittleEndian::write_i32(&mut buf[1..], n as i32);
+                    return self.write(&buf)
+                },
+                _ => (),
+            }
+        }
+
+        if self.protocol >= 2 {
+            let bytes = match n {
+                Some(n) => {
+                    let mut buf = [0; 8];
+                    LittleEndian::write_i64(&mut buf, n);
+                    encode_long(buf.to_vec())
+                },
+                None => match value.to_bigint() {
+                    Some(n) => encode_long(twos_complement(&n)),
+                    None => vec![],
+                },
+            };
+            if bytes.len() < 256 {
+                try!(self.write(&[LONG1, bytes.len() as u8]));
+            } else {
+                if bytes.len() > 0x7fffffff {
+                    return Err(Error::TooLarge)
+                }
+                try!(self.write_len(bytes.len(), None, Some(LONG4), None));
+            }
+            return self.write(&bytes)
+        }
+
+        match n {
+            Some(n @ -0x80000000 ... 0x7fffffff) => self.write(format!("I{}\n", n).as_bytes()),
+            _ => self.write(format!("L{}L\n", value).as_bytes()),
+        }
+    }
+
+    fn save_object<'a>(&mut self, value: &Value<'a>, obj: &Shared<'a, Object<'a>>, tasks: &mut Vec<Task<'a>>) -> Result<(), Error> {
+        let state = {
+            let o = obj.borrow();
+            let class = Task::Value(o.class.clone());
+            let args = match o.args {
+                Value::Tuple(ref args) => Some(args.clone()),
+                _ => None,
+            };
+            let line = match o.class {
+                Value::Global { ref module, ref name } => Some(Task::Line(INST, module.clone(), name.clone())),
+                _ => None,
+            };
+
+            match (o.construction, args, line) {
+                (Construction::NewObj, _, _) if self.protocol >= 2 => {
+                    tasks.push(class);
+                    tasks.push(Task::Value(o.args.clone()));
+                    tasks.push(Task::Opcode(NEWOBJ));
+                },
+                (Construction::NewObj, Some(args), _) => {
+                    // copyreg.__newobj__(class, *args)
+                    let mut items = vec![o.class.clone()];
+                    items.extend(args.borrow().iter().cloned());
+                    tasks.push(Task::Value(self.copyreg("__newobj__")));
+                    tasks.push(Task::Value(tuple(items)));
+                    tasks.push(Task::Opcode(REDUCE));
+                },
+                (Construction::NewObjEx, _, _) => {
+                    let kwargs = match o.kwargs {
+                        Some(ref kwargs) => kwargs.clone(),
+                        None => Value::Dict(Shared::new(Dict::new())),
+                    };
+                    if self.protocol >= 4 {
+                        tasks.push(class);
+                        tasks.push(Task::Value(o.args.clone()));
+                        tasks.push(Task::Value(kwargs));
+                        tasks.push(Task::Opcode(NEWOBJ_EX));
+                    } else {
+                        // copyreg.__newobj_ex__(class, args, kwargs)
+                        tasks.push(Task::Value(self.copyreg("__newobj_ex__")));
+                        tasks.push(Task::Value(tuple(vec![o.class.clone(), o.args.clone(), kwargs])));
+                        tasks.push(Task::Opcode(REDUCE));
+                    }
+                },
+                (Construction::Inst, Some(args), Some(line)) => {
+                    tasks.push(Task::Opcode(MARK));
+                    tasks.push(Task::Batch(Items::Values(args), 0, Add::None));
+                    tasks.push(line);
+                },
+                (Construction::Inst, Some(args), None) | (Construction::Obj, Some(args), _) => {
+                    tasks.push(Task::Opcode(MARK));
+                    tasks.push(class);
+                    tasks.push(Task::Batch(Items::Values(args), 0, Add::None));
+                    tasks.push(Task::Opcode(OBJ));
+                },
+                (Construction::Reduce, _, _) => {
+                    tasks.push(class);
+                    tasks.push(Task::Value(o.args.clone()));
+                    tasks.push(Task::Opcode(REDUCE));
+                },
+                _ => return Err(Error::InvalidArgs),
+            }
+
+            o.state.clone()
+        };
+
+        tasks.push(Task::Memoize(value.clone()));
+        let add = self.add(APPENDS, APPEND);
+        tasks.push(Task::Batch(Items::ObjectList(obj.clone()), 0, add));
+        let add = self.add(SETITEMS, SETITEM);
+        tasks.push(Task::Batch(Items::ObjectDict(obj.clone()), 0, add));
+        if let Some(state) = state {
+            tasks.push(Task::Value(state));
+            tasks.push(Task::Opcode(BUILD));
+        }
+        Ok(())
+    }
+
+    // Pushes the next batch of items and a task for the rest.
+    fn batch<'a>(&mut self, items: Items<'a>, start: usize, add: Add, tasks: &mut Vec<Task<'a>>) {
+        let len = items.len();
+        let end = min(len, start + BATCH_SIZE);
+        if start >= end {
+            return
+        }
+
+        match add {
+            Add::Batch(_, Some(one)) if end - start == 1 => {
+                items.push(start, tasks);
+                tasks.push(Task::Opcode(one));
+            },
+            Add::Batch(many, _) => {
+                tasks.push(Task::Opcode(MARK));
+                for i in start..end {
+                    items.push(i, tasks);
+                }
+                tasks.push(Task::Opcode(many));
+            },
+            Add::Each(one) => {
+                for i in start..end {
+                    items.push(i, tasks);
+                    tasks.push(Task::Opcode(one));
+                }
+            },
+            Add::None => {
+                for i in start..end {
+                    items.push(i, tasks);
+                }
+            },
+        }
+        tasks.push(Task::Batch(items, end, add));
+    }
+
+    fn add(&self, many: u8, one: u8) -> Add {
+        if self.protocol >= 1 {
+            Add::Batch(many, Some(one))
+        } else {
+            Add::Each(one)
+        }
+    }
+
+    // Python 2 names the modules `__builtin__` and `copy_reg`.
+    fn builtin<'a>(&self, name: &str) -> Value<'a> {
+        global(if self.protocol >= 3 { "builtins" } else { "__builtin__" }, name)
+    }
+
+    fn copyreg<'a>(&self, name: &str) -> Value<'a> {
+        global(if self.protocol >= 3 { "copyreg" } else { "copy_reg" }, name)
+    }
+}
+
+fn tuple<'a>(items: Vec<Value<'a>>) -> Value<'a> {
+    Value::Tuple(Shared::new(items))
+}
+
+fn global<'a>(module: &str, name: &str) -> Value<'a> {
+    Value::Global {
+        module: module.to_string(),
+        name: name.to_string(),
+    }
+}
+
+// The opcode and the length of what follows, the shortest of `short` with
+// one byte, `long` with four and `long8` with eight.
+fn len_header(len: usize, short: Option<u8>, long: Option<u8>, long8: Option<u8>) -> Result<([u8; 9], usize), Error> {
+    let mut buf = [0; 9];
+    let size = match (short, long, long8) {
+        (Some(short), _, _) if len < 256 => {
+            buf[0] = short;
+            buf[1] = len as u8;
+            2
+        },
+        (_, Some(long), _) if len as u64 <= 0xffffffff => {
+            buf[0] = long;
+            LittleEndian::write_u32(&mut buf[1..5], len as u32);
+            5
+        },
+        (_, _, Some(long8)) => {
+            buf[0] = long8;
+            LittleEndian::write_u64(&mut buf[1..], len as u64);
+            9
+        },
+        _ => return Err(Error::TooLarge),
+    };
+    Ok((buf, size))
+}
+
+// Little-endian two's complement, as LONG1 and LONG4 expect.
+fn twos_complement(n: &BigInt) -> Vec<u8> {
+    let (sign, mut bytes) = n.to_bytes_le();
+    if sign == Sign::Minus {
+        let mut carry = true;
+        for b in &mut bytes {
+            let (sum, overflow) = (!*b).overflowing_add(carry as u8);
+            *b = sum;
+            carry = overflow;
+        }
+        bytes.push(0xff);
+    } else {
+        bytes.push(0);
+    }
+    bytes
+}
+
+// Drops the bytes which only repeat the sign; zero has none.
+fn encode_long(mut bytes: Vec<u8>) -> Vec<u8> {
+    while bytes.len() > 1 {
+        let last = bytes[bytes.len() - 1];
+        let negative = bytes[bytes.len() - 2] & 0x80 != 0;
+        if (last == 0 && !negative) || (last == 0xff && negative) {
+            bytes.pop();
+        } else {
+            break
+        }
+    }
+    if bytes == [0] {
+        bytes.clear();
+    }
+    bytes
+}
+
+// Protocol 0 UNICODE is raw-unicode-escape, which writes Latin-1 characters
+// as their raw byte, with the characters which would end the line or be
+// read as escapes escaped too.
+fn raw_unicode_escape(s: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' | '\0' | '\n' | '\r' | '\x1a' => result.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes()),
+            c if (c as u32) < 0x100 => result.push(c as u32 as u8),
+            c if (c as u32) <= 0xffff => result.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes()),
+            c => result.extend_from_slice(format!("\\U{:08x}", c as u32).as_bytes()),
+        }
+    }
+    result
+}
+
+/// Writes a pickle piece by piece, so that long lists needn't be built as
+/// values first.
+///
+/// Lists are written like `Pickler` writes them in fast mode: their items
+/// are added in batches of 1000, but a last batch of a single item is
+/// added by APPENDS too, since it isn't known to be the last. Nothing is
+/// memoized.
+pub struct PickleWriter<W: Write> {
+    pickler: Pickler<W>,
+    // Items in the current batch of each open list
+    lists: Vec<usize>,
+    // Whether the value outside lists is written
+    done: bool,
+}
+
+impl<W: Write> PickleWriter<W> {
+    /// Starts a pickle, writing PROTO.
+    pub fn new(wr: W, protocol: u8) -> Result<Self, Error> {
+        let mut pickler = try!(Pickler::new(wr, protocol));
+        pickler.set_fast(true);
+        try!(pickler.write_proto());
+        Ok(PickleWriter {
+            pickler: pickler,
+            lists: Vec::new(),
+            done: false,
+        })
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.pickler.protocol()
+    }
+
+    /// Number of lists begun and not ended.
+    pub fn depth(&self) -> usize {
+        self.lists.len()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.pickler.get_ref()
+    }
+
+    /// Begins a list, the values written until `end_list` are its items.
+    pub fn begin_list(&mut self) -> Result<(), Error> {
+        try!(self.begin_item());
+        let bin = self.protocol() >= 1;
+        try!(self.pickler.write(if bin { &[EMPTY_LIST][..] } else { &[MARK, LIST][..] }));
+        self.lists.push(0);
+        Ok(())
+    }
+
+    pub fn end_list(&mut self) -> Result<(), Error> {
+        match self.lists.pop() {
+            Some(0) => (),
+            Some(_) => try!(self.pickler.write(&[APPENDS])),
+            None => return Err(Error::Unbalanced),
+        }
+        self.end_item()
+    }
+
+    pub fn push_int(&mut self, n: i64) -> Result<(), Error> {
+        try!(self.begin_item());
+        let value = if n as isize as i64 == n { Value::Int(n as isize) } else { Value::Long(BigInt::from(n)) };
+        try!(self.pickler.save_int(&value));
+        self.end_item()
+    }
+
+    pub fn push_str(&mut self, s: &str) -> Result<(), Error> {
+        try!(self.begin_item());
+        try!(self.pickler.write_unicode(s));
+        self.end_item()
+    }
+
+    /// Writes any value, e.g. a row as a tuple.
+    pub fn push_value<'a>(&mut self, value: &Value<'a>) -> Result<(), Error> {
+        try!(self.begin_item());
+        try!(self.pickler.save_all(value));
+        self.end_item()
+    }
+
+    /// Ends the pickle with STOP.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if !self.lists.is_empty() || !self.done {
+            return Err(Error::Unbalanced)
+        }
+        try!(self.pickler.write(&[STOP]));
+        try!(self.pickler.commit_frame(true));
+        Ok(self.pickler.into_inner())
+    }
+
+    fn begin_item(&mut self) -> Result<(), Error> {
+        // A pickle holds a single value
+        if self.lists.is_empty() && self.done {
+            return Err(Error::Unbalanced)
+        }
+        try!(self.pickler.commit_frame(false));
+        match self.lists.last() {
+            Some(&0) if self.pickler.protocol >= 1 => self.pickler.write(&[MARK]),
+            _ => Ok(()),
+        }
+    }
+
+    fn end_item(&mut self) -> Result<(), Error> {
+        let bin = self.protocol() >= 1;
+        match self.lists.last_mut() {
+            Some(_) if !bin => self.pickler.write(&[APPEND]),
+            Some(batch) => {
+                *batch += 1;
+                if *batch < BATCH_SIZE {
+                    return Ok(())
+                }
+                *batch = 0;
+                self.pickler.write(&[APPENDS])
+            },
+            None => {
+                self.done = true;
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Writes a pickle of `value` to `wr`.
+pub fn pickle<'a, W>(wr: &mut W, value: &Value<'a>, protocol: u8) -> Result<(), Error> where W: Write {
+    Pickler::new(wr, protocol).and_then(|mut pickler| pickler.dump(value))
+}
+
+/// Returns a pickle of `value`.
+pub fn to_vec<'a>(value: &Value<'a>, protocol: u8) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    try!(pickle(&mut buf, value, protocol));
+    Ok(buf)
+}
+
+// Writes a value alone, without PROTO, frames, memo or STOP, so that it
+// may replace one in a recorded pickle.
+pub(crate) fn encode_value<'a>(value: &Value<'a>, protocol: u8) -> Result<Vec<u8>, Error> {
+    let mut pickler = try!(Pickler::new(Vec::new(), protocol));
+    pickler.set_fast(true);
+    pickler.frame = None;
+    try!(pickler.save_all(value));
+    Ok(pickler.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor};
+    use std::rc::{Rc};
+
+    use num::bigint::{BigInt};
+
+    use machine::{unpickle};
+    use value::{Value, Shared, Object, Construction, Set, Dict};
+
+    use super::{Error, Pickler, PickleWriter, to_vec};
+
+    fn dump(value: &Value, protocol: u8, fast: bool) -> Vec<u8> {
+        let mut pickler = Pickler::new(Vec::new(), protocol).unwrap();
+        pickler.set_fast(fast);
+        pickler.dump(value).unwrap();
+        pickler.into_inner()
+    }
+
+    // Compares with CPython's pickles in fast mode
+    macro_rules! p {
+        ($value: expr, $protocol: expr, $buffer: expr) => (assert_eq!(dump(&$value, $protocol, true), &$buffer[..]))
+    }
+
+    // Compares with CPython's pickles
+    macro_rules! m {
+        ($value: expr, $protocol: expr, $buffer: expr) => (assert_eq!(dump(&$value, $protocol, false), &$buffer[..]))
+    }
+
+    // Objects are only equal to themselves, so their Debug is compared
+    fn round_trip(value: &Value, protocol: u8) {
+        let buf = to_vec(value, protocol).unwrap();
+        let result = unpickle(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(format!("{:?}", result), format!("{:?}", value));
+    }
+
+    fn global(module: &str, name: &str) -> Value<'static> {
+        Value::Global { module: module.to_string(), name: name.to_string() }
+    }
+
+    #[test]
+    fn test_int() {
+        p!(Value::Int(0), 0, b"I0\n.");
+        p!(Value::Int(255), 1, b"K\xff.");
+        p!(Value::Int(256), 1, b"M\x00\x01.");
+        p!(Value::Int(65536), 1, b"J\x00\x00\x01\x00.");
+        p!(Value::Int(-1), 2, b"\x80\x02J\xff\xff\xff\xff.");
+        p!(Value::Int(1 << 31), 1, b"L2147483648L\n.");
+        p!(Value::Int(1 << 31), 2, b"\x80\x02\x8a\x05\x00\x00\x00\x80\x00.");
+        p!(Value::Int(-(1 << 31) - 1), 2, b"\x80\x02\x8a\x05\xff\xff\xff\x7f\xff.");
+        p!(Value::Long(BigInt::from(1) << 63), 2, b"\x80\x02\x8a\t\x00\x00\x00\x00\x00\x00\x00\x80\x00.");
+        p!(Value::Long(-(BigInt::from(1) << 70)), 2, b"\x80\x02\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00\xc0.");
+        p!(Value::Long(BigInt::from(5)), 1, b"K\x05.");
+
+        let big = Value::Long(BigInt::from(-3) << 2100);
+        assert_eq!(to_vec(&big, 2).unwrap()[2], 0x8b);
+        round_trip(&big, 2);
+        round_trip(&big, 0);
+    }
+
+    #[test]
+    fn test_scalars() {
+        p!(Value::None, 0, b"N.");
+        p!(Value::Bool(true), 1, b"I01\n.");
+        p!(Value::Bool(false), 2, b"\x80\x02\x89.");
+        p!(Value::Float(1.5), 0, b"F1.5\n.");
+        p!(Value::Float(1.5), 1, b"G?\xf8\x00\x00\x00\x00\x00\x00.");
+        round_trip(&Value::Float(-1e-300), 0);
+    }
+
+    #[test]
+    fn test_strings() {
+        p!(Value::Unicode("ab".into()), 0, b"Vab\n.");
+        p!(Value::Unicode("a\\\n".into()), 0, b"Va\\u005c\\u000a\n.");
+        p!(Value::Unicode("\u{e9}\u{80}\u{ff}\u{100}\u{1f600}".into()), 0, b"V\xe9\x80\xff\\u0100\\U0001f600\n.");
+        p!(Value::Unicode("ab".into()), 3, b"\x80\x03X\x02\x00\x00\x00ab.");
+        p!(Value::Unicode("ab".into()), 4, b"\x80\x04\x95\x05\x00\x00\x00\x00\x00\x00\x00\x8c\x02ab.");
+        round_trip(&Value::Unicode("\u{e9}\u{20ac}\u{1f600}\r\0".into()), 0);
+        let long = "x".repeat(300);
+        assert_eq!(&to_vec(&Value::Unicode(long[..].into()), 4).unwrap()[..16], b"\x80\x04\x953\x01\x00\x00\x00\x00\x00\x00X,\x01\x00\x00");
+
+        p!(Value::String(b"a'\n"[..].into()), 0, b"S\"a'\\n\"\n.");
+        p!(Value::String(b"ab"[..].into()), 1, b"U\x02ab.");
+        round_trip(&Value::String(b"\x00\xff\\'\""[..].into()), 0);
+
+        p!(Value::Bytes(b"ab"[..].into()), 3, b"\x80\x03C\x02ab.");
+        p!(Value::Bytes(b"ab"[..].into()), 0, b"c_codecs\nencode\n(Vab\nVlatin1\ntR.");
+        p!(Value::Bytes(b""[..].into()), 2, b"\x80\x02c__builtin__\nbytes\n)R.");
+        p!(Value::ByteArray(b"ab".to_vec()), 3, b"\x80\x03cbuiltins\nbytearray\nC\x02ab\x85R.");
+        p!(Value::ByteArray(vec![]), 1, b"c__builtin__\nbytearray\n)R.");
+        p!(Value::ByteArray(b"ab".to_vec()), 5, b"\x80\x05\x95\x0c\x00\x00\x00\x00\x00\x00\x00\x96\x02\x00\x00\x00\x00\x00\x00\x00ab.");
+
+        // [bytearray(b'ab'), 'x']
+        let list = Value::List(Shared::new(vec![Value::ByteArray(b"ab".to_vec()), Value::Unicode("x".into())]));
+        m!(list, 2, b"\x80\x02]q\x00(c__builtin__\nbytearray\nq\x01c_codecs\nencode\nq\x02X\x02\x00\x00\x00abq\x03X\x06\x00\x00\x00latin1q\x04\x86q\x05Rq\x06\x85q\x07Rq\x08X\x01\x00\x00\x00xq\te.");
+        m!(list, 3, b"\x80\x03]q\x00(cbuiltins\nbytearray\nq\x01C\x02abq\x02\x85q\x03Rq\x04X\x01\x00\x00\x00xq\x05e.");
+        m!(list, 5, b"\x80\x05\x95\x15\x00\x00\x00\x00\x00\x00\x00]\x94(\x96\x02\x00\x00\x00\x00\x00\x00\x00ab\x94\x8c\x01x\x94e.");
+    }
+
+    #[test]
+    fn test_containers() {
+        let mut dict = Dict::new();
+        dict.insert(Value::Int(2), Value::Int(3));
+        let value = Value::Tuple(Shared::new(vec![
+            Value::List(Shared::new(vec![Value::Int(1)])),
+            Value::Dict(Shared::new(dict)),
+            Value::Tuple(Shared::new(vec![])),
+        ]));
+        p!(value, 0, b"((lI1\na(dI2\nI3\ns(tt.");
+        p!(value, 1, b"(]K\x01a}K\x02K\x03s)t.");
+        p!(value, 2, b"\x80\x02]K\x01a}K\x02K\x03s)\x87.");
+
+        let mut set = Set::new();
+        set.extend(vec![Value::Int(1), Value::Int(2)]);
+        p!(Value::Set(Shared::new(set.clone())), 2, b"\x80\x02c__builtin__\nset\n](K\x01K\x02e\x85R.");
+        p!(Value::Set(Shared::new(set.clone())), 4, b"\x80\x04\x95\x08\x00\x00\x00\x00\x00\x00\x00\x8f(K\x01K\x02\x90.");
+        p!(Value::FrozenSet(Shared::new(set.clone())), 4, b"\x80\x04\x95\x07\x00\x00\x00\x00\x00\x00\x00(K\x01K\x02\x91.");
+        for protocol in 0..6 {
+            round_trip(&Value::FrozenSet(Shared::new(set.clone())), protocol);
+        }
+
+        // 1001 items are appended by APPENDS and APPEND
+        let list = Value::List(Shared::new((0..1001).map(Value::Int).collect()));
+        let buf = dump(&list, 2, true);
+        assert_eq!(&buf[..4], b"\x80\x02](");
+        assert_eq!(&buf[buf.len() - 5..], b"M\xe8\x03a.");
+        round_trip(&list, 2);
+        round_trip(&list, 0);
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut obj = Object::new(Construction::Reduce, global("collections", "OrderedDict"), Value::Tuple(Shared::new(vec![])));
+        obj.dict_items.push((Value::Unicode("a".into()), Value::Int(1)));
+        obj.state = Some(Value::None);
+        let value = Value::Object(Shared::new(obj));
+        p!(value, 2, b"\x80\x02ccollections\nOrderedDict\n)RX\x01\x00\x00\x00aK\x01sNb.");
+        p!(value, 4, b"\x80\x04\x95&\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x8c\x0bOrderedDict\x93)R\x8c\x01aK\x01sNb.");
+
+        let args = Value::Tuple(Shared::new(vec![Value::Int(1)]));
+        // Older protocols call copyreg for NEWOBJ and NEWOBJ_EX
+        let constructions = [(Construction::NewObj, 2), (Construction::NewObjEx, 4), (Construction::Inst, 0), (Construction::Obj, 0)];
+        for &(construction, lowest) in &constructions {
+            let mut obj = Object::new(construction, global("geometry", "Point"), args.clone());
+            if construction == Construction::NewObjEx {
+                obj.kwargs = Some(Value::Dict(Shared::new(Dict::new())));
+            }
+            let obj = Value::Object(Shared::new(obj));
+            for protocol in lowest..6 {
+                round_trip(&obj, protocol);
+            }
+        }
+        let obj = Value::Object(Shared::new(Object::new(Construction::NewObj, global("geometry", "Point"), args)));
+        p!(obj, 1, b"ccopy_reg\n__newobj__\n(cgeometry\nPoint\nK\x01tR.");
+    }
+
+    #[test]
+    fn test_memo() {
+        m!(Value::Unicode("ab".into()), 0, b"Vab\np0\n.");
+        m!(Value::Unicode("ab".into()), 3, b"\x80\x03X\x02\x00\x00\x00abq\x00.");
+        m!(Value::Bytes(b"ab"[..].into()), 0, b"c_codecs\nencode\np0\n(Vab\np1\nVlatin1\np2\ntp3\nRp4\n.");
+
+        // The global and 'latin1' are memoized
+        let value = Value::List(Shared::new(vec![Value::Bytes(b"ab"[..].into()), Value::Bytes(b"cd"[..].into())]));
+        m!(value, 2, b"\x80\x02]q\x00(c_codecs\nencode\nq\x01X\x02\x00\x00\x00abq\x02X\x06\x00\x00\x00latin1q\x03\x86q\x04Rq\x05\
+                        h\x01X\x02\x00\x00\x00cdq\x06h\x03\x86q\x07Rq\x08e.");
+
+        let mut dict = Dict::new();
+        dict.insert(Value::Int(2), Value::Int(3));
+        let value = Value::Tuple(Shared::new(vec![
+            Value::List(Shared::new(vec![Value::Int(1)])),
+            Value::Dict(Shared::new(dict)),
+            Value::Tuple(Shared::new(vec![])),
+        ]));
+        m!(value, 0, b"((lp0\nI1\na(dp1\nI2\nI3\ns(ttp2\n.");
+        m!(value, 1, b"(]q\x00K\x01a}q\x01K\x02K\x03s)tq\x02.");
+
+        let mut set = Set::new();
+        set.extend(vec![Value::Int(1), Value::Int(2)]);
+        m!(Value::Set(Shared::new(set)), 2, b"\x80\x02c__builtin__\nset\nq\x00]q\x01(K\x01K\x02e\x85q\x02Rq\x03.");
+
+        let mut obj = Object::new(Construction::Reduce, global("collections", "OrderedDict"), Value::Tuple(Shared::new(vec![])));
+        obj.dict_items.push((Value::Unicode("a".into()), Value::Int(1)));
+        m!(Value::Object(Shared::new(obj)), 2, b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01X\x01\x00\x00\x00aq\x02K\x01s.");
+
+        // l = []; (l, l)
+        let list = Value::List(Shared::new(vec![]));
+        let value = Value::Tuple(Shared::new(vec![list.clone(), list]));
+        m!(value, 2, b"\x80\x02]q\x00h\x00\x86q\x01.");
+        p!(value, 2, b"\x80\x02]]\x86.");
+    }
+
+    #[test]
+    fn test_recursive() {
+        // l = []; l.append(l)
+        let list = Shared::new(vec![]);
+        list.borrow_mut().push(Value::List(list.clone()));
+        m!(Value::List(list.clone()), 0, b"(lp0\ng0\na.");
+        m!(Value::List(list.clone()), 1, b"]q\x00h\x00a.");
+        list.borrow_mut().clear();
+
+        // l = []; t = (l,); l.append(t), the tuple is written twice
+        let tuple = Value::Tuple(Shared::new(vec![Value::List(list.clone())]));
+        list.borrow_mut().push(tuple.clone());
+        m!(tuple, 0, b"((lp0\n(g0\ntp1\na00g1\n.");
+        m!(tuple, 1, b"(]q\x00(h\x00tq\x01a1h\x01.");
+        m!(tuple, 2, b"\x80\x02]q\x00h\x00\x85q\x01a0h\x01.");
+
+        let value = unpickle(&mut Cursor::new(dump(&tuple, 2, false))).unwrap();
+        let loaded = match value {
+            Value::Tuple(ref t) => match t.borrow()[0] {
+                Value::List(ref l) => l.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        assert!(match (&value, &loaded.borrow()[0]) {
+            (&Value::Tuple(ref t), &Value::Tuple(ref u)) => Rc::ptr_eq(t, u),
+            _ => false,
+        });
+        loaded.borrow_mut().clear();
+        list.borrow_mut().clear();
+    }
+
+    #[test]
+    fn test_frames() {
+        // ['a', b'x' * 65536, 'b'], the bytes are between the frames
+        let bytes = vec![b'x'; 65536];
+        let value = Value::List(Shared::new(vec![Value::Unicode("a".into()), Value::Bytes(bytes[..].into()), Value::Unicode("b".into())]));
+        let mut buffer = b"\x80\x04\x95\x07\x00\x00\x00\x00\x00\x00\x00]\x94(\x8c\x01a\x94B\x00\x00\x01\x00".to_vec();
+        buffer.extend_from_slice(&bytes);
+        buffer.extend_from_slice(b"\x95\x07\x00\x00\x00\x00\x00\x00\x00\x94\x8c\x01b\x94e.");
+        m!(value, 4, buffer);
+
+        // list(range(30000))
+        let value = Value::List(Shared::new((0..30000).map(Value::Int).collect()));
+        let buf = to_vec(&value, 4).unwrap();
+        assert_eq!(buf.len(), 89827);
+        assert_eq!(&buf[..11], b"\x80\x04\x95\x01\x00\x01\x00\x00\x00\x00\x00");
+        assert_eq!(&buf[65548..65557], b"\x95\xce\x5e\x00\x00\x00\x00\x00\x00");
+        assert!(buf.ends_with(b"M/ue."));
+
+        // Too short for a frame
+        p!(Value::Int(1), 4, b"\x80\x04K\x01.");
+    }
+
+    fn write(protocol: u8, items: usize) -> Vec<u8> {
+        let mut writer = PickleWriter::new(Vec::new(), protocol).unwrap();
+        writer.begin_list().unwrap();
+        writer.push_int(1).unwrap();
+        writer.push_str("a").unwrap();
+        writer.begin_list().unwrap();
+        writer.push_int(2).unwrap();
+        writer.push_int(3).unwrap();
+        writer.end_list().unwrap();
+        writer.begin_list().unwrap();
+        writer.end_list().unwrap();
+        writer.push_value(&Value::Tuple(Shared::new(vec![Value::None]))).unwrap();
+        writer.begin_list().unwrap();
+        for i in 0..items {
+            writer.push_int(i as i64).unwrap();
+        }
+        writer.end_list().unwrap();
+        writer.end_list().unwrap();
+        assert_eq!(writer.depth(), 0);
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_writer() {
+        // [1, 'a', [2, 3], [], (None,), []]
+        assert_eq!(write(0, 0), &b"(lI1\naVa\na(lI2\naI3\naa(la(Nta(la."[..]);
+        assert_eq!(write(2, 0), &b"\x80\x02](K\x01X\x01\x00\x00\x00a](K\x02K\x03e]N\x85]e."[..]);
+        assert_eq!(write(4, 0), &b"\x80\x04\x95\x14\x00\x00\x00\x00\x00\x00\x00](K\x01\x8c\x01a](K\x02K\x03e]N\x85]e."[..]);
+
+        // The same as a value, in batches and frames
+        for &protocol in &[0, 2, 4] {
+            for &items in &[1000, 2500, 100000] {
+                let list = Value::List(Shared::new((0..items).map(Value::Int).collect()));
+                let value = Value::List(Shared::new(vec![
+                    Value::Int(1),
+                    Value::Unicode("a".into()),
+                    Value::List(Shared::new(vec![Value::Int(2), Value::Int(3)])),
+                    Value::List(Shared::new(vec![])),
+                    Value::Tuple(Shared::new(vec![Value::None])),
+                    list,
+                ]));
+                assert_eq!(write(protocol, items as usize), dump(&value, protocol, true));
+            }
+        }
+
+        let mut writer = PickleWriter::new(Vec::new(), 2).unwrap();
+        writer.push_int(1 << 40).unwrap();
+        let buf = writer.finish().unwrap();
+        assert_eq!(unpickle(&mut Cursor::new(&buf[..])).unwrap().as_i64(), Some(1 << 40));
+    }
+
+    #[test]
+    fn test_errors() {
+        match Pickler::new(Vec::new(), 6) {
+            Err(Error::InvalidProto(6)) => (),
+            _ => assert!(false),
+        }
+
+        let list = Shared::new(vec![]);
+        list.borrow_mut().push(Value::List(list.clone()));
+        let mut pickler = Pickler::new(Vec::new(), 2).unwrap();
+        pickler.set_fast(true);
+        match pickler.dump(&Value::Tuple(Shared::new(vec![Value::List(list.clone())]))) {
+            Err(Error::RecursiveValue) => (),
+            _ => assert!(false),
+        }
+        list.borrow_mut().clear();
+
+        // Python has no tuples which contain themselves
+        let tuple = Shared::new(vec![]);
+        tuple.borrow_mut().push(Value::Tuple(tuple.clone()));
+        match to_vec(&Value::Tuple(tuple.clone()), 2) {
+            Err(Error::RecursiveValue) => (),
+            _ => assert!(false),
+        }
+        tuple.borrow_mut().clear();
+
+        let mut writer = PickleWriter::new(Vec::new(), 2).unwrap();
+        match writer.end_list() {
+            Err(Error::Unbalanced) => (),
+            _ => assert!(false),
+        }
+        writer.begin_list().unwrap();
+        match writer.finish() {
+            Err(Error::Unbalanced) => (),
+            _ => assert!(false),
+        }
+        match PickleWriter::new(Vec::new(), 2).unwrap().finish() {
+            Err(Error::Unbalanced) => (),
+            _ => assert!(false),
+        }
+
+        let mut writer = PickleWriter::new(Vec::new(), 2).unwrap();
+        writer.push_int(1).unwrap();
+        match writer.push_int(2) {
+            Err(Error::Unbalanced) => (),
+            _ => assert!(false),
+        }
+        match writer.begin_list() {
+            Err(Error::Unbalanced) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(writer.finish().unwrap(), b"\x80\x02K\x01.");
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pickles recorded opcode by opcode, to be written again as they were.
+
+use std::io::{Write, Error as IoError};
+use std::borrow::{Cow};
+use std::str;
+
+use byteorder::{ByteOrder, ReadBytesExt, LittleEndian};
+
+use value::{Value};
+use pickler::{encode_value, Error as PicklerError};
+
+use opcodes::*;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Pickler(err: PicklerError) {
+            from()
+        }
+        // The opcode pushes no value read from its argument
+        NoValue(index: usize)
+    }
+}
+
+// Opcodes which push a value read from their argument.
+const VALUES: &[u8] = &[
+    INT, BININT, BININT1, BININT2, LONG, LONG1, LONG4,
+    STRING, BINSTRING, SHORT_BINSTRING,
+    NONE, NEWTRUE, NEWFALSE,
+    UNICODE, BINUNICODE, SHORT_BINUNICODE, BINUNICODE8,
+    BINBYTES, SHORT_BINBYTES, BINBYTES8, BYTEARRAY8,
+    FLOAT, BINFLOAT, GLOBAL,
+];
+
+// Opcodes of protocol 1 which protocol 0 doesn't have.
+const BINARY: &[u8] = &[
+    BININT, BININT1, BININT2, BINSTRING, SHORT_BINSTRING, BINUNICODE, BINFLOAT,
+    EMPTY_LIST, APPENDS, EMPTY_TUPLE, EMPTY_DICT, SETITEMS, POP_MARK,
+    BINGET, LONG_BINGET, BINPUT, LONG_BINPUT, BINPERSID, OBJ,
+];
+
+fn memo_index(bytes: &[u8], memo_len: usize) -> Option<usize> {
+    match bytes[0] {
+        MEMOIZE => Some(memo_len),
+        BINPUT | BINGET => Some(bytes[1] as usize),
+        LONG_BINPUT | LONG_BINGET => (&bytes[1..]).read_u32::<LittleEndian>().ok().map(|n| n as usize),
+        // The digits between the opcode and the newline
+        PUT | GET => str::from_utf8(&bytes[1..bytes.len() - 1]).ok().and_then(|s| s.parse().ok()),
+        _ => None,
+    }
+}
+
+/// An opcode of a recorded pickle.
+pub struct Op<'a> {
+    code: u8,
+    offset: usize,
+    bytes: Cow<'a, [u8]>,
+    value: Option<Value<'a>>,
+    memo_index: Option<usize>,
+    // Where the frame started by FRAME ends
+    frame_end: Option<usize>,
+    patched: bool,
+}
+
+impl<'a> Op<'a> {
+    /// The opcode as it was read, e.g. BININT1 rather than BININT.
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    /// Position of the opcode in the pickle.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The opcode and its argument, or the opcodes which replaced them.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The value pushed by an opcode which reads it from its argument, like
+    /// an int or a string.
+    pub fn value(&self) -> Option<&Value<'a>> {
+        self.value.as_ref()
+    }
+
+    /// The memo index of PUT, GET, MEMOIZE and their binary forms.
+    pub fn memo_index(&self) -> Option<usize> {
+        self.memo_index
+    }
+
+    pub fn is_patched(&self) -> bool {
+        self.patched
+    }
+}
+
+/// The opcodes of a pickle with their arguments as they were written,
+/// recorded by `Machine::load_recorded`.
+///
+/// It's written again byte for byte, with the PROTO, FRAME and memo
+/// opcodes, and the same choice of opcodes for ints and strings, even
+/// where `Pickler` would choose others. A value may be replaced by `set`,
+/// then only it and the length of its frame change.
+pub struct Recording<'a> {
+    ops: Vec<Op<'a>>,
+}
+
+impl<'a> Recording<'a> {
+    pub(crate) fn new() -> Self {
+        Recording {
+            ops: Vec::new(),
+        }
+    }
+
+    // Records the opcode read from `bytes`, given the top of the stack
+    // after it, the memo length before it and where the last frame ends.
+    pub(crate) fn push(&mut self, offset: usize, bytes: &'a [u8], top: Option<&Value<'a>>, memo_len: usize, frame_end: usize) {
+        let code = bytes[0];
+        self.ops.push(Op {
+            code: code,
+            offset: offset,
+            bytes: Cow::Borrowed(bytes),
+            value: if VALUES.contains(&code) { top.cloned() } else { None },
+            memo_index: memo_index(bytes, memo_len),
+            frame_end: if code == FRAME { Some(frame_end) } else { None },
+            patched: false,
+        })
+    }
+
+    pub fn ops(&self) -> &[Op<'a>] {
+        &self.ops
+    }
+
+    /// The protocol given by PROTO, otherwise 1 if the pickle has opcodes
+    /// protocol 0 doesn't have, or 0.
+    pub fn protocol(&self) -> u8 {
+        match self.ops.first() {
+            Some(op) if op.code == PROTO => op.bytes[1],
+            _ if self.ops.iter().any(|op| BINARY.contains(&op.code)) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Replaces the value pushed by the opcode at `i`, which has to be one
+    /// of the opcodes with a `value`. The new value is written like
+    /// `Pickler` writes it in fast mode, in the protocol of the pickle.
+    pub fn set(&mut self, i: usize, value: Value<'a>) -> Result<(), Error> {
+        let protocol = self.protocol();
+        match self.ops.get_mut(i) {
+            Some(op) if op.value.is_some() => {
+                op.bytes = Cow::Owned(try!(encode_value(&value, protocol)));
+                op.value = Some(value);
+                op.patched = true;
+                Ok(())
+            },
+            _ => Err(Error::NoValue(i)),
+        }
+    }
+
+    // New length of the frame started at `i`, if an opcode in it changed.
+    fn frame_len(&self, i: usize) -> Option<usize> {
+        self.ops[i].frame_end.and_then(|end| {
+            let ops = self.ops[i + 1..].iter().take_while(|op| op.offset < end);
+            if ops.clone().any(|op| op.patched) {
+                Some(ops.map(|op| op.bytes.len()).sum())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn write<W>(&self, wr: &mut W) -> Result<(), IoError> where W: Write {
+        for (i, op) in self.ops.iter().enumerate() {
+            match self.frame_len(i) {
+                Some(len) => {
+                    let mut buf = [FRAME, 0, 0, 0, 0, 0, 0, 0, 0];
+                    LittleEndian::write_u64(&mut buf[1..], len as u64);
+                    try!(wr.write_all(&buf))
+                },
+                None => try!(wr.write_all(&op.bytes)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing to a vector doesn't fail
+        self.write(&mut buf).unwrap();
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use machine::{record};
+    use value::{Value, Shared};
+    use opcodes::*;
+
+    use super::{Error, Recording};
+
+    // {'version': 1, 'name': 'x', 'items': [1.5, None, True, 10 ** 20]}
+    const PICKLES: &[&[u8]] = &[
+        b"(dp0\nVversion\np1\nI1\nsVname\np2\nVx\np3\nsVitems\np4\n(lp5\nF1.5\naNaI01\naL100000000000000000000L\nas.",
+        b"\x80\x02}q\x00(X\x07\x00\x00\x00versionq\x01K\x01X\x04\x00\x00\x00nameq\x02X\x01\x00\x00\x00xq\x03X\x05\x00\x00\x00itemsq\x04]q\x05(G?\xf8\x00\x00\x00\x00\x00\x00N\x88\x8a\t\x00\x00\x10c-^\xc7k\x05eu.",
+        b"\x80\x04\x95>\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x07version\x94K\x01\x8c\x04name\x94\x8c\x01x\x94\x8c\x05items\x94]\x94(G?\xf8\x00\x00\x00\x00\x00\x00N\x88\x8a\t\x00\x00\x10c-^\xc7k\x05eu.",
+    ];
+
+    // The same with 'version': 300
+    const PATCHED: &[&[u8]] = &[
+        b"(dp0\nVversion\np1\nI300\nsVname\np2\nVx\np3\nsVitems\np4\n(lp5\nF1.5\naNaI01\naL100000000000000000000L\nas.",
+        b"\x80\x02}q\x00(X\x07\x00\x00\x00versionq\x01M,\x01X\x04\x00\x00\x00nameq\x02X\x01\x00\x00\x00xq\x03X\x05\x00\x00\x00itemsq\x04]q\x05(G?\xf8\x00\x00\x00\x00\x00\x00N\x88\x8a\t\x00\x00\x10c-^\xc7k\x05eu.",
+        b"\x80\x04\x95?\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x07version\x94M,\x01\x8c\x04name\x94\x8c\x01x\x94\x8c\x05items\x94]\x94(G?\xf8\x00\x00\x00\x00\x00\x00N\x88\x8a\t\x00\x00\x10c-^\xc7k\x05eu.",
+    ];
+
+    fn codes(recording: &Recording) -> Vec<u8> {
+        recording.ops().iter().filter(|op| op.value().is_some()).map(|op| op.code()).collect()
+    }
+
+    // Index of the opcode of the value which follows the key.
+    fn find<'a>(recording: &Recording<'a>, key: &'a str) -> usize {
+        let ops = recording.ops();
+        let i = ops.iter().position(|op| op.value() == Some(&Value::Unicode(key.into()))).unwrap();
+        i + 1 + ops[i + 1..].iter().position(|op| op.value().is_some()).unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        for buffer in PICKLES {
+            let (value, recording) = record(buffer).unwrap();
+            assert_eq!(&recording.to_vec()[..], *buffer);
+            assert_eq!(format!("{}", value), "{u'version': 1, u'name': u'x', u'items': [1.5, None, True, 100000000000000000000]}");
+        }
+
+        let (_, recording) = record(PICKLES[0]).unwrap();
+        assert_eq!(recording.protocol(), 0);
+        assert_eq!(codes(&recording), vec![UNICODE, INT, UNICODE, UNICODE, UNICODE, FLOAT, NONE, INT, LONG]);
+
+        let (_, recording) = record(PICKLES[2]).unwrap();
+        assert_eq!(recording.protocol(), 4);
+        assert_eq!(codes(&recording), vec![SHORT_BINUNICODE, BININT1, SHORT_BINUNICODE, SHORT_BINUNICODE, SHORT_BINUNICODE,
+                                           BINFLOAT, NONE, NEWTRUE, LONG1]);
+        let ops = recording.ops();
+        assert_eq!((ops[1].code(), ops[1].offset()), (FRAME, 2));
+        let memo: Vec<_> = ops.iter().filter_map(|op| op.memo_index()).collect();
+        assert_eq!(memo, vec![0, 1, 2, 3, 4, 5]);
+
+        // Not the opcodes Pickler would choose: [1, 'a', 'a'] with BININT,
+        // BINUNICODE and LONG_BINGET
+        let buffer = b"\x80\x02]q\x00(J\x01\x00\x00\x00X\x01\x00\x00\x00aq\x01j\x01\x00\x00\x00e.";
+        let (value, recording) = record(&buffer[..]).unwrap();
+        assert_eq!(&recording.to_vec()[..], &buffer[..]);
+        assert_eq!(format!("{}", value), "[1, u'a', u'a']");
+        assert_eq!(recording.ops().iter().filter_map(|op| op.memo_index()).collect::<Vec<_>>(), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn test_set() {
+        for (buffer, patched) in PICKLES.iter().zip(PATCHED) {
+            let (_, mut recording) = record(buffer).unwrap();
+            let i = find(&recording, "version");
+            recording.set(i, Value::Int(300)).unwrap();
+            assert!(recording.ops()[i].is_patched());
+            assert_eq!(&recording.to_vec()[..], *patched);
+        }
+
+        let (_, mut recording) = record(PICKLES[2]).unwrap();
+        let i = find(&recording, "version");
+        recording.set(i, Value::List(Shared::new(vec![Value::None]))).unwrap();
+        let buffer = recording.to_vec();
+        let (value, _) = record(&buffer).unwrap();
+        assert_eq!(format!("{}", value), "{u'version': [None], u'name': u'x', u'items': [1.5, None, True, 100000000000000000000]}");
+
+        match recording.set(2, Value::None) {
+            Err(Error::NoValue(2)) => (),
+            _ => assert!(false),
+        }
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright (c) 2016 Fedor Gogolev <knsd@knsd.net>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `Debug` and `Display` of values. Both stop at containers which contain
+//! themselves and don't recurse, so that values of any depth can be printed.
+
+use std::collections::{HashSet};
+use std::fmt::{self, Debug, Display, Formatter, Write};
+
+use value::{Value, Bytes, Shared, Object, Set, Dict};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Debug,
+    Repr,
+}
+
+enum Piece<'a> {
+    Text(String),
+    Value(Value<'a>),
+}
+
+enum Contents<'a> {
+    Items(Shared<'a, Vec<Value<'a>>>),
+    Dict(Shared<'a, Dict<'a>>),
+    Set(Shared<'a, Set<'a>>),
+    // In reverse order
+    Pieces(Vec<Piece<'a>>),
+}
+
+// Container being printed.
+struct Frame<'a> {
+    contents: Contents<'a>,
+    // Items, or keys and values, printed so far
+    printed: usize,
+    ptr: usize,
+    close: &'static str,
+}
+
+// Prints a value unless it's a container, returns whether it was printed.
+fn scalar(f: &mut Formatter, style: Style, value: &Value) -> Result<bool, fmt::Error> {
+    try!(match (style, value) {
+        (_, &Value::None) => f.write_str("None"),
+        (Style::Debug, &Value::Bool(b)) => write!(f, "Bool({:?})", b),
+        (Style::Debug, &Value::Int(i)) => write!(f, "Int({:?})", i),
+        (Style::Debug, &Value::Long(ref l)) => write!(f, "Long({})", l),
+        (Style::Debug, &Value::Float(x)) => write!(f, "Float({:?})", x),
+        (Style::Debug, &Value::String(ref s)) => write!(f, "String({:?})", s),
+        (Style::Debug, &Value::Unicode(ref s)) => write!(f, "Unicode({:?})", s),
+        (Style::Debug, &Value::Bytes(ref s)) => write!(f, "Bytes({:?})", s),
+        (Style::Debug, &Value::ByteArray(ref s)) => write!(f, "ByteArray({:?})", Bytes::Borrowed(s)),
+        (Style::Debug, &Value::Global { ref module, ref name }) => {
+            write!(f, "Global {{ module: {:?}, name: {:?} }}", module, name)
+        },
+
+        (Style::Repr, &Value::Bool(true)) => f.write_str("True"),
+        (Style::Repr, &Value::Bool(false)) => f.write_str("False"),
+        (Style::Repr, &Value::Int(i)) => write!(f, "{}", i),
+        (Style::Repr, &Value::Long(ref l)) => write!(f, "{}", l),
+        (Style::Repr, &Value::Float(x)) => repr_float(f, x),
+        (Style::Repr, &Value::String(ref s)) => repr_bytes(f, s),
+        (Style::Repr, &Value::Unicode(ref s)) => {
+            try!(f.write_str("u"));
+            repr_unicode(f, s)
+        },
+        (Style::Repr, &Value::Bytes(ref s)) => {
+            try!(f.write_str("b"));
+            repr_bytes(f, s)
+        },
+        (Style::Repr, &Value::ByteArray(ref s)) => {
+            try!(f.write_str("bytearray(b"));
+            try!(repr_bytes(f, s));
+            f.write_str(")")
+        },
+        (Style::Repr, &Value::Global { ref module, ref name }) => write!(f, "{}.{}", module, name),
+        (Style::Repr, &Value::Object(ref obj)) => match obj.borrow().class {
+            Value::Global { ref module, ref name } => write!(f, "<{}.{} object>", module, name),
+            _ => f.write_str("<object>"),
+        },
+
+        _ => return Ok(false),
+    });
+    Ok(true)
+}
+
+fn object_pieces<'a>(obj: &Object<'a>) -> Vec<Piece<'a>> {
+    let mut pieces = Vec::new();
+
+    macro_rules! text {
+        ($($arg: tt)*) => (pieces.push(Piece::Text(format!($($arg)*))))
+    }
+    macro_rules! value {
+        ($value: expr) => (pieces.push(Piece::Value($value.clone())))
+    }
+    macro_rules! option {
+        ($value: expr) => (match $value {
+            None => text!("None"),
+            Some(ref v) => {
+                text!("Some(");
+                value!(v);
+                text!(")");
+            },
+        })
+    }
+
+    text!("construction: {:?}, class: ", obj.construction);
+    value!(obj.class);
+    text!(", args: ");
+    value!(obj.args);
+    text!(", kwargs: ");
+    option!(obj.kwargs);
+    text!(", state: ");
+    option!(obj.state);
+    text!(", list_items: [");
+    for (i, item) in obj.list_items.iter().enumerate() {
+        if i > 0 {
+            text!(", ");
+        }
+        value!(item);
+    }
+    text!("], dict_items: [");
+    for (i, &(ref key, ref value)) in obj.dict_items.iter().enumerate() {
+        text!("{}(", if i > 0 { ", " } else { "" });
+        value!(key);
+        text!(", ");
+        value!(value);
+        text!(")");
+    }
+    text!("]");
+
+    pieces.reverse();
+    pieces
+}
+
+// Starts printing a container.
+fn open<'a>(f: &mut Formatter, style: Style, value: &Value<'a>, path: &HashSet<usize>) -> Result<Option<Frame<'a>>, fmt::Error> {
+    let debug = style == Style::Debug;
+    let (ptr, contents, open, close, recursive) = match *value {
+        Value::List(ref items) if debug => (items.as_ptr() as usize, Contents::Items(items.clone()), "List([", "])", "List([...])"),
+        Value::List(ref items) => (items.as_ptr() as usize, Contents::Items(items.clone()), "[", "]", "[...]"),
+        Value::Tuple(ref items) if debug => (items.as_ptr() as usize, Contents::Items(items.clone()), "Tuple([", "])", "Tuple([...])"),
+        Value::Tuple(ref items) => {
+            let close = if items.borrow().len() == 1 { ",)" } else { ")" };
+            (items.as_ptr() as usize, Contents::Items(items.clone()), "(", close, "(...)")
+        },
+        Value::Dict(ref dict) if debug => (dict.as_ptr() as usize, Contents::Dict(dict.clone()), "Dict({", "})", "Dict({...})"),
+        Value::Dict(ref dict) => (dict.as_ptr() as usize, Contents::Dict(dict.clone()), "{", "}", "{...}"),
+        Value::Set(ref set) if debug => (set.as_ptr() as usize, Contents::Set(set.clone()), "Set({", "})", "Set({...})"),
+        Value::Set(ref set) if set.borrow().is_empty() => return f.write_str("set()").map(|_| None),
+        Value::Set(ref set) => (set.as_ptr() as usize, Contents::Set(set.clone()), "{", "}", "set(...)"),
+        Value::FrozenSet(ref set) if debug => {
+            (set.as_ptr() as usize, Contents::Set(set.clone()), "FrozenSet({", "})", "FrozenSet({...})")
+        },
+        Value::FrozenSet(ref set) if set.borrow().is_empty() => return f.write_str("frozenset()").map(|_| None),
+        Value::FrozenSet(ref set) => (set.as_ptr() as usize, Contents::Set(set.clone()), "frozenset({", "})", "frozenset(...)"),
+        Value::Object(ref obj) => {
+            let pieces = object_pieces(&obj.borrow());
+            (obj.as_ptr() as usize, Contents::Pieces(pieces), "Object(Object { ", " })", "Object(...)")
+        },
+        _ => return Ok(None),
+    };
+
+    if path.contains(&ptr) {
+        try!(f.write_str(recursive));
+        return Ok(None)
+    }
+    try!(f.write_str(open));
+    Ok(Some(Frame {
+        contents: contents,
+        printed: 0,
+        ptr: ptr,
+        close: close,
+    }))
+}
+
+// Prints the items of a container up to the next nested container, which
+// is returned, or up to the end.
+fn next<'a>(f: &mut Formatter, style: Style, frame: &mut Frame<'a>) -> Result<Option<Value<'a>>, fmt::Error> {
+    macro_rules! item {
+        ($separator: expr, $value: expr) => ({
+            frame.printed += 1;
+            if frame.printed > 1 {
+                try!(f.write_str($separator));
+            }
+            if !try!(scalar(f, style, $value)) {
+                return Ok(Some($value.clone()))
+            }
+        })
+    }
+
+    match frame.contents {
+        Contents::Items(ref items) => {
+            let items = items.borrow();
+            while let Some(item) = items.get(frame.printed) {
+                item!(", ", item);
+            }
+        },
+        Contents::Set(ref set) => {
+            let set = set.borrow();
+            while let Some(item) = set.iter().as_slice().get(frame.printed) {
+                item!(", ", item);
+            }
+        },
+        Contents::Dict(ref dict) => {
+            let dict = dict.borrow();
+            while let Some(&(ref key, ref value)) = dict.iter().as_slice().get(frame.printed / 2) {
+                if frame.printed % 2 == 0 {
+                    item!(", ", key);
+                } else {
+                    // The separator is printed even before the first value
+                    frame.printed += 1;
+                    try!(f.write_str(": "));
+                    if !try!(scalar(f, style, value)) {
+                        return Ok(Some(value.clone()))
+                    }
+                }
+            }
+        },
+        Contents::Pieces(ref mut pieces) => {
+            while let Some(piece) = pieces.pop() {
+                match piece {
+                    Piece::Text(text) => try!(f.write_str(&text)),
+                    Piece::Value(value) => {
+                        if !try!(scalar(f, style, &value)) {
+                            return Ok(Some(value))
+                        }
+                    },
+                }
+            }
+        },
+    }
+    Ok(None)
+}
+
+fn print(f: &mut Formatter, style: Style, value: &Value) -> fmt::Result {
+    if try!(scalar(f, style, value)) {
+        return Ok(())
+    }
+
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut path = HashSet::new();
+    let mut value = Some(value.clone());
+    loop {
+        if let Some(value) = value.take() {
+            if let Some(frame) = try!(open(f, style, &value, &path)) {
+                path.insert(frame.ptr);
+                stack.push(frame);
+            }
+        }
+
+        value = match stack.last_mut() {
+            None => return Ok(()),
+            Some(frame) => try!(next(f, style, frame)),
+        };
+        if value.is_none() {
+            if let Some(frame) = stack.pop() {
+                path.remove(&frame.ptr);
+                try!(f.write_str(frame.close));
+            }
+        }
+    }
+}
+
+impl<'a> Debug for Value<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        print(f, Style::Debug, self)
+    }
+}
+
+// Python 2 `str` and Python 3 `bytes` are escaped the same way.
+fn repr_bytes(f: &mut Formatter, s: &[u8]) -> fmt::Result {
+    let quote = if s.contains(&b'\'') && !s.contains(&b'"') { '"' } else { '\'' };
+    try!(f.write_char(quote));
+    for &c in s {
+        try!(match c {
+            b'\\' => f.write_str("\\\\"),
+            b'\t' => f.write_str("\\t"),
+            b'\n' => f.write_str("\\n"),
+            b'\r' => f.write_str("\\r"),
+            c if c as char == quote => write!(f, "\\{}", quote),
+            c if c < b' ' || c >= 0x7f => write!(f, "\\x{:02x}", c),
+            c => f.write_char(c as char),
+        });
+    }
+    f.write_char(quote)
+}
+
+// Like Python 2 `unicode`, all non-ASCII characters are escaped.
+fn repr_unicode(f: &mut Formatter, s: &str) -> fmt::Result {
+    let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+    try!(f.write_char(quote));
+    for c in s.chars() {
+        try!(match c {
+            '\\' => f.write_str("\\\\"),
+            '\t' => f.write_str("\\t"),
+            '\n' => f.write_str("\\n"),
+            '\r' => f.write_str("\\r"),
+            c if c == quote => write!(f, "\\{}", quote),
+            c if c < ' ' || (c as u32) >= 0x7f && (c as u32) <= 0xff => write!(f, "\\x{:02x}", c as u32),
+            c if (c as u32) > 0xffff => write!(f, "\\U{:08x}", c as u32),
+            c if (c as u32) > 0xff => write!(f, "\\u{:04x}", c as u32),
+            c => f.write_char(c),
+        });
+    }
+    f.write_char(quote)
+}
+
+// Shortest digits which read back as the same float, in fixed notation
+// unless the exponent is below -4 or above 15.
+fn repr_float(f: &mut Formatter, x: f64) -> fmt::Result {
+    if x.is_nan() {
+        return f.write_str("nan")
+    }
+    if x.is_infinite() {
+        return f.write_str(if x > 0.0 { "inf" } else { "-inf" })
+    }
+
+    let s = format!("{:e}", x);
+    let (mantissa, exponent) = match s.find('e') {
+        Some(i) => (&s[..i], s[i + 1..].parse::<i32>().unwrap_or(0)),
+        None => (&s[..], 0),
+    };
+    let (sign, mantissa) = if mantissa.starts_with('-') { ("-", &mantissa[1..]) } else { ("", mantissa) };
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+
+    try!(f.write_str(sign));
+    if exponent >= 16 || exponent < -4 {
+        let (first, rest) = digits.split_at(1);
+        try!(f.write_str(first));
+        if !rest.is_empty() {
+            try!(write!(f, ".{}", rest));
+        }
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(f, "e{}{:02}", sign, exponent.abs())
+    } else if exponent < 0 {
+        write!(f, "0.{}{}", "0".repeat((-exponent - 1) as usize), digits)
+    } else {
+        let point = exponent as usize + 1;
+        if digits.len() > point {
+            write!(f, "{}.{}", &digits[..point], &digits[point..])
+        } else {
+            write!(f, "{}{}.0", digits, "0".repeat(point - digits.len()))
+        }
+    }
+}
+
+/// Renders values like Python's `repr()` does. `String` is rendered as
+/// Python 2 `str`, `Unicode` as Python 2 `unicode` with the `u` prefix and
+/// `Bytes` as Python 3 `bytes`. Globals are rendered as `module.name` and
+/// objects as `<module.name object>`.
+impl<'a> Display for Value<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        print(f, Style::Repr, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigInt};
+
+    use value::{Value, Shared, Set, Dict};
+
+    macro_rules! r {
+        ($value: expr, $repr: expr) => (assert_eq!(format!("{}", $value), $repr))
+    }
+
+    #[test]
+    fn test_repr() {
+        r!(Value::None, "None");
+        r!(Value::Bool(true), "True");
+        r!(Value::Int(-1), "-1");
+        r!(Value::Long(BigInt::from(1) << 70), "1180591620717411303424");
+
+        r!(Value::String(b"a'b\n\xff"[..].into()), "\"a'b\\n\\xff\"");
+        r!(Value::String(b"'\""[..].into()), "'\\'\"'");
+        r!(Value::Unicode("\u{e9}\u{20ac}\u{1f600}\\".into()), "u'\\xe9\\u20ac\\U0001f600\\\\'");
+        r!(Value::Bytes(b"\x00"[..].into()), "b'\\x00'");
+        r!(Value::ByteArray(b"ab".to_vec()), "bytearray(b'ab')");
+
+        let tuple = |items| Value::Tuple(Shared::new(items));
+        r!(tuple(vec![]), "()");
+        r!(tuple(vec![Value::Int(1)]), "(1,)");
+        r!(Value::List(Shared::new(vec![Value::Int(1), tuple(vec![])])), "[1, ()]");
+
+        let mut dict = Dict::new();
+        dict.insert(Value::Unicode("a".into()), Value::None);
+        dict.insert(Value::Int(1), Value::Float(0.5));
+        r!(Value::Dict(Shared::new(dict)), "{u'a': None, 1: 0.5}");
+
+        let mut set = Set::new();
+        r!(Value::Set(Shared::new(set.clone())), "set()");
+        r!(Value::FrozenSet(Shared::new(set.clone())), "frozenset()");
+        set.extend(vec![Value::Int(1), Value::Int(2)]);
+        r!(Value::Set(Shared::new(set.clone())), "{1, 2}");
+        r!(Value::FrozenSet(Shared::new(set)), "frozenset({1, 2})");
+
+        r!(Value::Global { module: "os".to_string(), name: "path".to_string() }, "os.path");
+    }
+
+    #[test]
+    fn test_repr_float() {
+        r!(Value::Float(1.0), "1.0");
+        r!(Value::Float(-0.0), "-0.0");
+        r!(Value::Float(0.1), "0.1");
+        r!(Value::Float(123.456), "123.456");
+        r!(Value::Float(0.0001), "0.0001");
+        r!(Value::Float(0.00001), "1e-05");
+        r!(Value::Float(1.5e-10), "1.5e-10");
+        r!(Value::Float(1e15), "1000000000000000.0");
+        r!(Value::Float(1e16), "1e+16");
+        r!(Value::Float(1.25e100), "1.25e+100");
+        r!(Value::Float(::std::f64::INFINITY), "inf");
+        r!(Value::Float(::std::f64::NEG_INFINITY), "-inf");
+        r!(Value::Float(::std::f64::NAN), "nan");
+    }
+
+    #[test]
+    fn test_recursive() {
+        let list = Shared::new(vec![Value::Int(1)]);
+        list.borrow_mut().push(Value::List(list.clone()));
+        let value = Value::List(list.clone());
+        r!(value, "[1, [...]]");
+        assert_eq!(format!("{:?}", value), "List([Int(1), List([...])])");
+
+        let dict = Shared::new(Dict::new());
+        dict.borrow_mut().insert(Value::Unicode("a".into()), Value::Dict(dict.clone()));
+        let tuple = Value::Tuple(Shared::new(vec![Value::Dict(dict.clone()), Value::Dict(dict.clone())]));
+        r!(tuple, "({u'a': {...}}, {u'a': {...}})");
+        assert_eq!(format!("{:?}", tuple), "Tuple([Dict({Unicode(\"a\"): Dict({...})}), Dict({Unicode(\"a\"): Dict({...})})])");
+
+        // Break the cycles
+        list.borrow_mut().clear();
+        dict.borrow_mut().insert(Value::Unicode("a".into()), Value::None);
+    }
+}
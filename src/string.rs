@@ -62,7 +62,13 @@ pub fn unescape(s: &[u8], unicode: bool) -> Result<Vec<u8>, Error> {
         let c = read!();
 
         if c != b'\\' {
-            buf.push(c);
+            // Raw bytes of raw-unicode-escape are Latin-1 characters
+            if unicode && c >= 0x80 {
+                let character = c as char;
+                push_char!(character);
+            } else {
+                buf.push(c);
+            }
             continue
         }
 
@@ -172,5 +178,7 @@ mod tests {
         assert_eq!(unescape(b"f\\U00002663oo", true).unwrap(), b"f\xe2\x99\xa3oo");
         assert_eq!(unescape(b"f\\u2663oo", true).unwrap(), b"f\xe2\x99\xa3oo");
         assert_eq!(unescape(b"f\\N{SNOWMAN}oo", true).unwrap(), b"f\xe2\x98\x83oo");
+        assert_eq!(unescape(b"f\xe9oo", true).unwrap(), b"f\xc3\xa9oo");
+        assert_eq!(unescape(b"f\xe9oo", false).unwrap(), b"f\xe9oo");
     }
 }
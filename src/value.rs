@@ -8,19 +8,1017 @@
 
 use std::cell::{RefCell};
 use std::rc::{Rc};
+use std::slice::{Iter};
+use std::mem::{replace};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{DefaultHasher};
+use std::hash::{Hash, Hasher};
+use std::fmt::{self, Write};
+use std::ops::{Deref};
+use std::marker::{PhantomData};
 
+use num::{FromPrimitive, ToPrimitive};
 use num::bigint::{BigInt};
 
-#[derive(Debug, Clone)]
-pub enum Value {
+/// Decoded Python object.
+///
+/// Values compare like their Python counterparts do, e.g. `1 == 1.0 ==
+/// True`, and equal values hash equally. NaN is never equal to anything,
+/// itself included. Strings compare like in Python 3: Python 2 strings are
+/// bytes, equal to bytes and bytearrays, but never to unicode.
+///
+/// `Display` renders values like Python's `repr()`.
+///
+/// Values decoded by `from_slice` borrow their strings and bytes from the
+/// input, hence the lifetime.
+#[derive(Clone)]
+pub enum Value<'a> {
     None,
     Bool(bool),
     Int(isize),
     Long(BigInt),
     Float(f64),
-    String(Vec<u8>),
-    Unicode(String),
-    List(Rc<RefCell<Vec<Value>>>),
-    Tuple(Rc<RefCell<Vec<Value>>>),
-    Dict(Rc<RefCell<Vec<(Value, Value)>>>),
+    String(Bytes<'a>),
+    Unicode(Text<'a>),
+    Bytes(Bytes<'a>),
+    ByteArray(Vec<u8>),
+    List(Shared<'a, Vec<Value<'a>>>),
+    Tuple(Shared<'a, Vec<Value<'a>>>),
+    Dict(Shared<'a, Dict<'a>>),
+    Set(Shared<'a, Set<'a>>),
+    FrozenSet(Shared<'a, Set<'a>>),
+    Global {
+        module: String,
+        name: String,
+    },
+    Object(Shared<'a, Object<'a>>),
+}
+
+/// Payload of `Value::String` and `Value::Bytes`, borrowed from the input
+/// or reference counted, so that cloning it never copies the bytes.
+#[derive(Clone)]
+pub enum Bytes<'a> {
+    Borrowed(&'a [u8]),
+    Shared(Rc<[u8]>),
+}
+
+impl<'a> Bytes<'a> {
+    pub fn is_borrowed(&self) -> bool {
+        match *self {
+            Bytes::Borrowed(_) => true,
+            Bytes::Shared(_) => false,
+        }
+    }
+
+    /// Copies the bytes, shared ones too.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Bytes::Borrowed(s) => s.to_vec(),
+            Bytes::Shared(s) => s.to_vec(),
+        }
+    }
+}
+
+impl<'a> Deref for Bytes<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Bytes::Borrowed(s) => s,
+            Bytes::Shared(ref s) => s,
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for Bytes<'a> {
+    fn from(s: &'a [u8]) -> Self {
+        Bytes::Borrowed(s)
+    }
+}
+
+// Copies the bytes into a new `Rc<[u8]>`.
+impl<'a> From<Vec<u8>> for Bytes<'a> {
+    fn from(v: Vec<u8>) -> Self {
+        Bytes::Shared(v.into())
+    }
+}
+
+impl<'a, 'b> PartialEq<Bytes<'b>> for Bytes<'a> {
+    fn eq(&self, other: &Bytes<'b>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<'a> PartialEq<[u8]> for Bytes<'a> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self[..] == *other
+    }
+}
+
+impl<'a> fmt::Debug for Bytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str("b\""));
+        for &c in self.iter() {
+            for e in (c as char).escape_default() {
+                try!(f.write_char(e));
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+/// Payload of `Value::Unicode`, borrowed from the input or reference
+/// counted.
+#[derive(Clone)]
+pub enum Text<'a> {
+    Borrowed(&'a str),
+    Shared(Rc<str>),
+}
+
+impl<'a> Text<'a> {
+    pub fn is_borrowed(&self) -> bool {
+        match *self {
+            Text::Borrowed(_) => true,
+            Text::Shared(_) => false,
+        }
+    }
+
+    /// Copies the text, shared one too.
+    pub fn into_string(self) -> String {
+        match self {
+            Text::Borrowed(s) => s.to_string(),
+            Text::Shared(s) => s.to_string(),
+        }
+    }
+}
+
+impl<'a> Deref for Text<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            Text::Borrowed(s) => s,
+            Text::Shared(ref s) => s,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Text<'a> {
+    fn from(s: &'a str) -> Self {
+        Text::Borrowed(s)
+    }
+}
+
+// Copies the text into a new `Rc<str>`.
+impl<'a> From<String> for Text<'a> {
+    fn from(s: String) -> Self {
+        Text::Shared(s.into())
+    }
+}
+
+impl<'a, 'b> PartialEq<Text<'b>> for Text<'a> {
+    fn eq(&self, other: &Text<'b>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<'a> PartialEq<str> for Text<'a> {
+    fn eq(&self, other: &str) -> bool {
+        self[..] == *other
+    }
+}
+
+impl<'a, 'b> PartialEq<&'b str> for Text<'a> {
+    fn eq(&self, other: &&'b str) -> bool {
+        self[..] == **other
+    }
+}
+
+impl<'a> fmt::Debug for Text<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self[..], f)
+    }
+}
+
+/// Contents of a container value.
+pub trait Contents<'a> {
+    /// Moves the values out of the container.
+    fn take_values(&mut self, values: &mut Vec<Value<'a>>);
+}
+
+/// Shared container, an `Rc<RefCell<T>>` which it dereferences to, but
+/// dropped without recursion however deeply nested its values are.
+pub struct Shared<'a, T: Contents<'a>>(Rc<RefCell<T>>, PhantomData<&'a ()>);
+
+impl<'a, T: Contents<'a>> Shared<'a, T> {
+    pub fn new(contents: T) -> Self {
+        Shared(Rc::new(RefCell::new(contents)), PhantomData)
+    }
+
+    // Moves the values out if this is the last reference to the container,
+    // before dropping it would do it recursively.
+    fn take_last(&self, values: &mut Vec<Value<'a>>) {
+        if Rc::strong_count(&self.0) == 1 {
+            self.0.borrow_mut().take_values(values);
+        }
+    }
+}
+
+impl<'a, T: Contents<'a>> Deref for Shared<'a, T> {
+    type Target = Rc<RefCell<T>>;
+
+    fn deref(&self) -> &Rc<RefCell<T>> {
+        &self.0
+    }
+}
+
+impl<'a, T: Contents<'a>> Clone for Shared<'a, T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone(), PhantomData)
+    }
+}
+
+impl<'a, T: Contents<'a>> From<T> for Shared<'a, T> {
+    fn from(contents: T) -> Self {
+        Shared::new(contents)
+    }
+}
+
+impl<'a, T: Contents<'a>> Drop for Shared<'a, T> {
+    fn drop(&mut self) {
+        let mut values = Vec::new();
+        self.take_last(&mut values);
+
+        // Values are emptied before they are dropped
+        while let Some(value) = values.pop() {
+            match value {
+                Value::List(ref items) | Value::Tuple(ref items) => items.take_last(&mut values),
+                Value::Dict(ref dict) => dict.take_last(&mut values),
+                Value::Set(ref set) | Value::FrozenSet(ref set) => set.take_last(&mut values),
+                Value::Object(ref obj) => obj.take_last(&mut values),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<'a> Contents<'a> for Vec<Value<'a>> {
+    fn take_values(&mut self, values: &mut Vec<Value<'a>>) {
+        values.extend(self.drain(..));
+    }
+}
+
+/// Opcode which created an object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Construction {
+    /// `class(*args)`
+    Reduce,
+    /// `class.__new__(class, *args)`
+    NewObj,
+    /// `class.__new__(class, *args, **kwargs)`
+    NewObjEx,
+    /// `module.name(*args)`, protocol 0
+    Inst,
+    /// `class(*args)`, protocol 1
+    Obj,
+}
+
+/// Record of the calls which construct a Python object.
+#[derive(Debug, Clone)]
+pub struct Object<'a> {
+    pub construction: Construction,
+    /// Called class or function, usually a `Value::Global`.
+    pub class: Value<'a>,
+    /// Positional arguments, a `Value::Tuple`.
+    pub args: Value<'a>,
+    /// Keyword arguments, a `Value::Dict`; only set by NEWOBJ_EX.
+    pub kwargs: Option<Value<'a>>,
+    /// Argument of BUILD, i.e. of `__setstate__`.
+    pub state: Option<Value<'a>>,
+    /// Items added by APPEND and APPENDS.
+    pub list_items: Vec<Value<'a>>,
+    /// Items added by SETITEM and SETITEMS.
+    pub dict_items: Vec<(Value<'a>, Value<'a>)>,
+}
+
+impl<'a> Object<'a> {
+    pub fn new(construction: Construction, class: Value<'a>, args: Value<'a>) -> Self {
+        Object {
+            construction: construction,
+            class: class,
+            args: args,
+            kwargs: None,
+            state: None,
+            list_items: Vec::new(),
+            dict_items: Vec::new(),
+        }
+    }
+}
+
+impl<'a> Contents<'a> for Object<'a> {
+    fn take_values(&mut self, values: &mut Vec<Value<'a>>) {
+        values.push(replace(&mut self.class, Value::None));
+        values.push(replace(&mut self.args, Value::None));
+        values.extend(self.kwargs.take());
+        values.extend(self.state.take());
+        values.extend(self.list_items.drain(..));
+        for (key, value) in self.dict_items.drain(..) {
+            values.push(key);
+            values.push(value);
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Whether Python could use the value as a dict key or a set item.
+    pub fn is_hashable(&self) -> bool {
+        let mut tuples = Vec::new();
+        match *self {
+            Value::ByteArray(_) | Value::List(_) | Value::Dict(_) | Value::Set(_) => return false,
+            Value::Tuple(ref items) => tuples.push(items.clone()),
+            _ => (),
+        }
+
+        while let Some(items) = tuples.pop() {
+            for item in items.borrow().iter() {
+                match *item {
+                    Value::ByteArray(_) | Value::List(_) | Value::Dict(_) | Value::Set(_) => return false,
+                    Value::Tuple(ref items) => tuples.push(items.clone()),
+                    _ => (),
+                }
+            }
+        }
+        true
+    }
+
+    /// Value of an integer, whether it's an `Int` or a `Long`, if it fits
+    /// into an `i64`. Decoding makes integers which fit into an `isize` an
+    /// `Int`, so a `Long` is always a large number.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i as i64),
+            Value::Long(ref l) => l.to_i64(),
+            _ => None,
+        }
+    }
+
+    /// Value of an integer, whether it's an `Int` or a `Long`.
+    pub fn to_bigint(&self) -> Option<BigInt> {
+        match *self {
+            Value::Int(i) => Some(BigInt::from(i as i64)),
+            Value::Long(ref l) => Some(l.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Positions of the entries of a table by the hashes of their keys. Entries
+// with the same hash are chained, the last one added comes first.
+#[derive(Clone, Default)]
+struct Index {
+    first: HashMap<u64, usize>,
+    // Next position in the chain of each position
+    next: Vec<Option<usize>>,
+}
+
+impl Index {
+    fn find<F>(&self, hash: u64, mut is_key: F) -> Option<usize> where F: FnMut(usize) -> bool {
+        let mut position = self.first.get(&hash).cloned();
+        while let Some(i) = position {
+            if is_key(i) {
+                return Some(i)
+            }
+            position = self.next[i];
+        }
+        None
+    }
+
+    fn positions(&self, hash: u64) -> Vec<usize> {
+        let mut positions = Vec::new();
+        self.find(hash, |i| { positions.push(i); false });
+        positions
+    }
+
+    // Entries are only ever appended, `position` is the number of them.
+    fn insert(&mut self, hash: u64, position: usize) {
+        debug_assert_eq!(position, self.next.len());
+        let next = self.first.insert(hash, position);
+        self.next.push(next);
+    }
+}
+
+fn hash_of(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Python set or frozenset: no two items are equal in the sense of Python's
+/// `==`, so e.g. `1`, `1.0` and `True` are the same item. Items are kept in
+/// insertion order.
+#[derive(Clone, Default)]
+pub struct Set<'a> {
+    items: Vec<Value<'a>>,
+    index: Index,
+}
+
+impl<'a> Set<'a> {
+    pub fn new() -> Self {
+        Set {
+            items: Vec::new(),
+            index: Index::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn find(&self, value: &Value<'a>, hash: u64) -> Option<usize> {
+        let items = &self.items;
+        self.index.find(hash, |i| items[i] == *value)
+    }
+
+    pub fn contains(&self, value: &Value<'a>) -> bool {
+        self.find(value, hash_of(value)).is_some()
+    }
+
+    // Positions of the items which have the same hash as `value`.
+    fn candidates(&self, value: &Value) -> Vec<usize> {
+        self.index.positions(hash_of(value))
+    }
+
+    /// Adds `value` unless an equal item is already present, in which case
+    /// the present one is kept, as in Python. Returns whether it was added.
+    pub fn insert(&mut self, value: Value<'a>) -> bool {
+        let hash = hash_of(&value);
+        if self.find(&value, hash).is_some() {
+            return false
+        }
+        self.index.insert(hash, self.items.len());
+        self.items.push(value);
+        true
+    }
+
+    pub fn iter<'b>(&'b self) -> Iter<'b, Value<'a>> {
+        self.items.iter()
+    }
+}
+
+impl<'a> Extend<Value<'a>> for Set<'a> {
+    fn extend<I>(&mut self, iter: I) where I: IntoIterator<Item=Value<'a>> {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl<'a> Contents<'a> for Set<'a> {
+    fn take_values(&mut self, values: &mut Vec<Value<'a>>) {
+        values.extend(self.items.drain(..));
+        self.index = Index::default();
+    }
+}
+
+impl<'a> fmt::Debug for Set<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.items.iter()).finish()
+    }
+}
+
+/// Python dict: keys are compared like Python does, entries are kept in
+/// insertion order.
+#[derive(Clone, Default)]
+pub struct Dict<'a> {
+    entries: Vec<(Value<'a>, Value<'a>)>,
+    index: Index,
+}
+
+impl<'a> Dict<'a> {
+    pub fn new() -> Self {
+        Dict {
+            entries: Vec::new(),
+            index: Index::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn find(&self, key: &Value<'a>, hash: u64) -> Option<usize> {
+        let entries = &self.entries;
+        self.index.find(hash, |i| entries[i].0 == *key)
+    }
+
+    pub fn get(&self, key: &Value<'a>) -> Option<&Value<'a>> {
+        self.find(key, hash_of(key)).map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_mut(&mut self, key: &Value<'a>) -> Option<&mut Value<'a>> {
+        match self.find(key, hash_of(key)) {
+            None => None,
+            Some(i) => Some(&mut self.entries[i].1),
+        }
+    }
+
+    pub fn contains_key(&self, key: &Value<'a>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of `key` and returns the previous one. Like in Python,
+    /// replacing a value keeps both the key which is already present and the
+    /// position of the entry.
+    pub fn insert(&mut self, key: Value<'a>, value: Value<'a>) -> Option<Value<'a>> {
+        let hash = hash_of(&key);
+        match self.find(&key, hash) {
+            Some(i) => Some(replace(&mut self.entries[i].1, value)),
+            None => {
+                self.index.insert(hash, self.entries.len());
+                self.entries.push((key, value));
+                None
+            },
+        }
+    }
+
+    pub fn iter<'b>(&'b self) -> Iter<'b, (Value<'a>, Value<'a>)> {
+        self.entries.iter()
+    }
+}
+
+impl<'a> Extend<(Value<'a>, Value<'a>)> for Dict<'a> {
+    fn extend<I>(&mut self, iter: I) where I: IntoIterator<Item=(Value<'a>, Value<'a>)> {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<'a> Contents<'a> for Dict<'a> {
+    fn take_values(&mut self, values: &mut Vec<Value<'a>>) {
+        for (key, value) in self.entries.drain(..) {
+            values.push(key);
+            values.push(value);
+        }
+        self.index = Index::default();
+    }
+}
+
+impl<'a> fmt::Debug for Dict<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.entries.iter().map(|&(ref k, ref v)| (k, v))).finish()
+    }
+}
+
+enum Number<'a> {
+    Int(i64),
+    Long(&'a BigInt),
+    Float(f64),
+}
+
+impl<'a> Value<'a> {
+    fn as_number<'b>(&'b self) -> Option<Number<'b>> {
+        match *self {
+            Value::Bool(b) => Some(Number::Int(b as i64)),
+            Value::Int(i) => Some(Number::Int(i as i64)),
+            Value::Long(ref l) => Some(Number::Long(l)),
+            Value::Float(f) => Some(Number::Float(f)),
+            _ => None,
+        }
+    }
+}
+
+// Python compares integers and floats exactly, without rounding either.
+fn equal_numbers(a: Number, b: Number) -> bool {
+    match (a, b) {
+        (Number::Int(a), Number::Int(b)) => a == b,
+        (Number::Int(a), Number::Long(b)) | (Number::Long(b), Number::Int(a)) => BigInt::from(a) == *b,
+        (Number::Long(a), Number::Long(b)) => a == b,
+        (Number::Float(a), Number::Float(b)) => a == b,
+        (Number::Int(i), Number::Float(f)) | (Number::Float(f), Number::Int(i)) => {
+            float_as_i64(f) == Some(i)
+        },
+        (Number::Long(l), Number::Float(f)) | (Number::Float(f), Number::Long(l)) => {
+            f.fract() == 0.0 && BigInt::from_f64(f).map_or(false, |f| f == *l)
+        },
+    }
+}
+
+fn float_as_i64(f: f64) -> Option<i64> {
+    if f.fract() == 0.0 && f >= -9223372036854775808.0 && f < 9223372036854775808.0 {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+// Integral numbers hash the same whatever their type is.
+fn hash_number<H>(n: Number, state: &mut H) where H: Hasher {
+    match n {
+        Number::Int(i) => i.hash(state),
+        Number::Long(l) => match l.to_i64() {
+            Some(i) => i.hash(state),
+            None => l.hash(state),
+        },
+        Number::Float(f) => match float_as_i64(f) {
+            Some(i) => i.hash(state),
+            None => match BigInt::from_f64(f) {
+                Some(ref l) if f.fract() == 0.0 => l.hash(state),
+                _ => f.to_bits().hash(state),
+            },
+        },
+    }
+}
+
+// Comparison of the items of two containers, done without recursion so that
+// deep values can't overflow the stack.
+enum Comparison<'a> {
+    Items(Shared<'a, Vec<Value<'a>>>, Shared<'a, Vec<Value<'a>>>, usize),
+    Dict(Shared<'a, Dict<'a>>, Shared<'a, Dict<'a>>, usize),
+    // Positions of the items of the second set which may be equal to the
+    // current item of the first one
+    Set(Shared<'a, Set<'a>>, Shared<'a, Set<'a>>, usize, Option<Vec<usize>>),
+}
+
+enum Step<'a> {
+    Equal(bool),
+    Compare(Comparison<'a>),
+}
+
+impl<'a> Comparison<'a> {
+    fn pair(&self) -> (usize, usize) {
+        match *self {
+            Comparison::Items(ref a, ref b, _) => (a.as_ptr() as usize, b.as_ptr() as usize),
+            Comparison::Dict(ref a, ref b, _) => (a.as_ptr() as usize, b.as_ptr() as usize),
+            Comparison::Set(ref a, ref b, _, _) => (a.as_ptr() as usize, b.as_ptr() as usize),
+        }
+    }
+
+    // Compares items until one needs a nested comparison or the result is
+    // known. `last` is the result of the previous nested comparison.
+    fn step(&mut self, last: Option<bool>, comparing: &HashSet<(usize, usize)>) -> Step<'a> {
+        match *self {
+            Comparison::Items(ref a, ref b, ref mut i) => {
+                if last == Some(false) {
+                    return Step::Equal(false)
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                while *i < a.len() && *i < b.len() {
+                    *i += 1;
+                    match start(&a[*i - 1], &b[*i - 1], comparing) {
+                        Step::Equal(true) => (),
+                        step => return step,
+                    }
+                }
+                Step::Equal(a.len() == b.len())
+            },
+            Comparison::Dict(ref a, ref b, ref mut i) => {
+                if last == Some(false) {
+                    return Step::Equal(false)
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                while *i < a.len() {
+                    let (ref key, ref value) = a.entries[*i];
+                    *i += 1;
+                    let step = match b.get(key) {
+                        None => return Step::Equal(false),
+                        Some(v) => start(value, v, comparing),
+                    };
+                    match step {
+                        Step::Equal(true) => (),
+                        step => return step,
+                    }
+                }
+                Step::Equal(a.len() == b.len())
+            },
+            Comparison::Set(ref a, ref b, ref mut i, ref mut candidates) => {
+                if last == Some(true) {
+                    *i += 1;
+                    *candidates = None;
+                }
+                let (a, b) = (a.borrow(), b.borrow());
+                while *i < a.len() {
+                    let item = &a.items[*i];
+                    if candidates.is_none() {
+                        *candidates = Some(b.candidates(item));
+                    }
+                    let candidate = match candidates.as_mut().and_then(|c| c.pop()) {
+                        None => return Step::Equal(false),
+                        Some(c) => c,
+                    };
+                    match start(item, &b.items[candidate], comparing) {
+                        Step::Equal(true) => {
+                            *i += 1;
+                            *candidates = None;
+                        },
+                        Step::Equal(false) => (),
+                        step => return step,
+                    }
+                }
+                Step::Equal(a.len() == b.len())
+            },
+        }
+    }
+}
+
+// Compares scalars, containers are compared item by item later unless the
+// result is obvious. Pairs of containers already being compared are part of
+// a cycle and are considered equal.
+fn start<'a>(a: &Value<'a>, b: &Value<'a>, comparing: &HashSet<(usize, usize)>) -> Step<'a> {
+    if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+        return Step::Equal(equal_numbers(a, b))
+    }
+
+    let comparison = match (a, b) {
+        (&Value::None, &Value::None) => return Step::Equal(true),
+
+        (&Value::Unicode(ref a), &Value::Unicode(ref b)) => return Step::Equal(a == b),
+        (&Value::String(ref a), &Value::String(ref b)) | (&Value::String(ref a), &Value::Bytes(ref b)) |
+        (&Value::Bytes(ref a), &Value::String(ref b)) | (&Value::Bytes(ref a), &Value::Bytes(ref b)) => return Step::Equal(a == b),
+        (&Value::ByteArray(ref a), &Value::ByteArray(ref b)) => return Step::Equal(a == b),
+        (&Value::String(ref a), &Value::ByteArray(ref b)) | (&Value::ByteArray(ref b), &Value::String(ref a)) |
+        (&Value::Bytes(ref a), &Value::ByteArray(ref b)) | (&Value::ByteArray(ref b), &Value::Bytes(ref a)) => return Step::Equal(a[..] == b[..]),
+
+        (&Value::List(ref a), &Value::List(ref b)) | (&Value::Tuple(ref a), &Value::Tuple(ref b)) => {
+            if Rc::ptr_eq(a, b) || a.borrow().len() != b.borrow().len() {
+                return Step::Equal(Rc::ptr_eq(a, b))
+            }
+            Comparison::Items(a.clone(), b.clone(), 0)
+        },
+        (&Value::Dict(ref a), &Value::Dict(ref b)) => {
+            if Rc::ptr_eq(a, b) || a.borrow().len() != b.borrow().len() {
+                return Step::Equal(Rc::ptr_eq(a, b))
+            }
+            Comparison::Dict(a.clone(), b.clone(), 0)
+        },
+        (&Value::Set(ref a), &Value::Set(ref b)) | (&Value::Set(ref a), &Value::FrozenSet(ref b)) |
+        (&Value::FrozenSet(ref a), &Value::Set(ref b)) | (&Value::FrozenSet(ref a), &Value::FrozenSet(ref b)) => {
+            if Rc::ptr_eq(a, b) || a.borrow().len() != b.borrow().len() {
+                return Step::Equal(Rc::ptr_eq(a, b))
+            }
+            Comparison::Set(a.clone(), b.clone(), 0, None)
+        },
+
+        (&Value::Global { module: ref m1, name: ref n1 }, &Value::Global { module: ref m2, name: ref n2 }) => {
+            return Step::Equal(m1 == m2 && n1 == n2)
+        },
+        (&Value::Object(ref a), &Value::Object(ref b)) => return Step::Equal(Rc::ptr_eq(a, b)),
+
+        _ => return Step::Equal(false),
+    };
+
+    if comparing.contains(&comparison.pair()) {
+        return Step::Equal(true)
+    }
+    Step::Compare(comparison)
+}
+
+fn equal<'a>(a: &Value<'a>, b: &Value<'a>) -> bool {
+    let mut stack = Vec::new();
+    let mut comparing = HashSet::new();
+
+    match start(a, b, &comparing) {
+        Step::Equal(result) => return result,
+        Step::Compare(comparison) => {
+            comparing.insert(comparison.pair());
+            stack.push(comparison);
+        },
+    }
+
+    // The result of a finished comparison goes to the one below it
+    let mut last = None;
+    loop {
+        let step = match stack.last_mut() {
+            None => return last.unwrap_or(true),
+            Some(comparison) => comparison.step(last.take(), &comparing),
+        };
+        match step {
+            Step::Equal(result) => {
+                if let Some(comparison) = stack.pop() {
+                    comparing.remove(&comparison.pair());
+                }
+                last = Some(result);
+            },
+            Step::Compare(comparison) => {
+                comparing.insert(comparison.pair());
+                stack.push(comparison);
+            },
+        }
+    }
+}
+
+impl<'a> PartialEq for Value<'a> {
+    fn eq(&self, other: &Value<'a>) -> bool {
+        equal(self, other)
+    }
+}
+
+// Values nested deeper than this don't affect the hash, which keeps equal
+// values hashing equally without recursing arbitrarily deep.
+const HASH_DEPTH: usize = 8;
+
+fn hash_value<H>(value: &Value, state: &mut H, depth: usize) where H: Hasher {
+    match *value {
+        Value::Bool(_) | Value::Int(_) | Value::Long(_) | Value::Float(_) => {
+            0u8.hash(state);
+            if let Some(n) = value.as_number() {
+                hash_number(n, state)
+            }
+        },
+        Value::None => 1u8.hash(state),
+        // Strings, bytes and bytearrays equal across types have the same
+        // bytes
+        Value::String(ref s) | Value::Bytes(ref s) => {
+            2u8.hash(state);
+            s[..].hash(state)
+        },
+        Value::ByteArray(ref s) => {
+            2u8.hash(state);
+            s[..].hash(state)
+        },
+        Value::Unicode(ref s) => {
+            9u8.hash(state);
+            s.as_bytes().hash(state)
+        },
+        // Mutable containers are unhashable in Python, only hash their
+        // size so that cycles aren't followed.
+        Value::List(ref items) => {
+            3u8.hash(state);
+            items.borrow().len().hash(state)
+        },
+        Value::Tuple(ref items) => {
+            4u8.hash(state);
+            let items = items.borrow();
+            items.len().hash(state);
+            if depth > 0 {
+                for item in items.iter() {
+                    hash_value(item, state, depth - 1);
+                }
+            }
+        },
+        Value::Dict(ref dict) => {
+            5u8.hash(state);
+            dict.borrow().len().hash(state)
+        },
+        // Equal sets may have different order
+        Value::Set(ref items) | Value::FrozenSet(ref items) => {
+            6u8.hash(state);
+            let items = items.borrow();
+            items.len().hash(state);
+            if depth > 0 {
+                items.iter().fold(0u64, |acc, item| {
+                    let mut hasher = DefaultHasher::new();
+                    hash_value(item, &mut hasher, depth - 1);
+                    acc ^ hasher.finish()
+                }).hash(state);
+            }
+        },
+        Value::Global { ref module, ref name } => {
+            7u8.hash(state);
+            module.hash(state);
+            name.hash(state)
+        },
+        Value::Object(ref obj) => {
+            8u8.hash(state);
+            (obj.as_ptr() as usize).hash(state)
+        },
+    }
+}
+
+impl<'a> Hash for Value<'a> {
+    fn hash<H>(&self, state: &mut H) where H: Hasher {
+        hash_value(self, state, HASH_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigInt};
+
+    use super::{Value, Shared, Set, Dict, hash_of};
+
+    #[test]
+    fn test_set() {
+        let mut set = Set::new();
+        assert!(set.insert(Value::Int(1)));
+        assert!(!set.insert(Value::Float(1.0)));
+        assert!(!set.insert(Value::Bool(true)));
+        assert!(!set.insert(Value::Long(BigInt::from(1))));
+        assert!(set.insert(Value::Float(1.5)));
+        assert!(set.insert(Value::Float(::std::f64::NAN)));
+        assert!(set.insert(Value::Float(::std::f64::NAN)));
+        assert_eq!(set.len(), 4);
+
+        assert!(set.insert(Value::Unicode("a".into())));
+        assert!(set.insert(Value::String(b"a"[..].into())));
+        assert!(!set.insert(Value::Bytes(b"a"[..].into())));
+        assert!(!set.insert(Value::ByteArray(b"a".to_vec())));
+
+        let tuple = |items| Value::Tuple(Shared::new(items));
+        assert!(set.insert(tuple(vec![Value::Int(1), Value::None])));
+        assert!(set.contains(&tuple(vec![Value::Float(1.0), Value::None])));
+        assert!(!set.contains(&tuple(vec![Value::None, Value::Int(1)])));
+    }
+
+    #[test]
+    fn test_is_hashable() {
+        let list = Value::List(Shared::new(vec![]));
+        assert!(!list.is_hashable());
+        assert!(!Value::Tuple(Shared::new(vec![list])).is_hashable());
+        assert!(Value::Tuple(Shared::new(vec![Value::None])).is_hashable());
+    }
+
+    #[test]
+    fn test_eq() {
+        assert_eq!(Value::Int(1), Value::Float(1.0));
+        assert_eq!(Value::Int(1), Value::Bool(true));
+        assert_eq!(Value::Int(-5), Value::Long(BigInt::from(-5)));
+        assert_eq!(Value::Long(BigInt::from(1) << 70), Value::Float(1180591620717411303424.0));
+        assert!(Value::Int(1) != Value::Float(1.5));
+        assert!(Value::Float(::std::f64::NAN) != Value::Float(::std::f64::NAN));
+
+        assert_eq!(Value::String(b"a"[..].into()), Value::Bytes(b"a"[..].into()));
+        assert_eq!(Value::String(b"a"[..].into()), Value::ByteArray(b"a".to_vec()));
+        assert_eq!(Value::Bytes(b"a"[..].into()), Value::ByteArray(b"a".to_vec()));
+        assert!(Value::String(b"a"[..].into()) != Value::Unicode("a".into()));
+        assert!(Value::Bytes(b"a"[..].into()) != Value::Unicode("a".into()));
+        assert!(Value::None != Value::Bool(false));
+
+        let tuple = |items| Value::Tuple(Shared::new(items));
+        let list = |items| Value::List(Shared::new(items));
+        assert_eq!(tuple(vec![Value::Int(1)]), tuple(vec![Value::Float(1.0)]));
+        assert!(tuple(vec![Value::Int(1)]) != list(vec![Value::Int(1)]));
+
+        let (a, b) = (list(vec![]), list(vec![]));
+        if let (&Value::List(ref a_items), &Value::List(ref b_items)) = (&a, &b) {
+            a_items.borrow_mut().push(a.clone());
+            b_items.borrow_mut().push(b.clone());
+        }
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_integers() {
+        let long = BigInt::from(1) << 70;
+        assert_eq!(Value::Int(-5).as_i64(), Some(-5));
+        assert_eq!(Value::Long(BigInt::from(-5)).as_i64(), Some(-5));
+        assert_eq!(Value::Long(long.clone()).as_i64(), None);
+        assert_eq!(Value::Int(-5).to_bigint(), Some(BigInt::from(-5)));
+        assert_eq!(Value::Long(long.clone()).to_bigint(), Some(long));
+        assert_eq!(Value::Float(1.0).as_i64(), None);
+        assert_eq!(Value::None.to_bigint(), None);
+    }
+
+    #[test]
+    fn test_hash() {
+        let long = Value::Long(BigInt::from(1) << 70);
+        assert_eq!(hash_of(&Value::Int(1)), hash_of(&Value::Float(1.0)));
+        assert_eq!(hash_of(&Value::Int(1)), hash_of(&Value::Bool(true)));
+        assert_eq!(hash_of(&Value::Int(1)), hash_of(&Value::Long(BigInt::from(1))));
+        assert_eq!(hash_of(&long), hash_of(&Value::Float(1180591620717411303424.0)));
+        assert_eq!(hash_of(&Value::Float(0.0)), hash_of(&Value::Float(-0.0)));
+        assert_eq!(hash_of(&Value::String(b"a"[..].into())), hash_of(&Value::Bytes(b"a"[..].into())));
+        assert_eq!(hash_of(&Value::String(b"a"[..].into())), hash_of(&Value::ByteArray(b"a".to_vec())));
+
+        let mut a = Set::new();
+        a.extend(vec![Value::Int(1), Value::Int(2)]);
+        let mut b = Set::new();
+        b.extend(vec![Value::Int(2), Value::Float(1.0)]);
+        let (a, b) = (Value::FrozenSet(Shared::new(a)), Value::Set(Shared::new(b)));
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+    }
+
+    #[test]
+    fn test_dict() {
+        let mut dict = Dict::new();
+        assert_eq!(dict.insert(Value::Int(1), Value::None), None);
+        assert_eq!(dict.insert(Value::Unicode("a".into()), Value::Int(2)), None);
+        assert_eq!(dict.insert(Value::Float(1.0), Value::Int(3)), Some(Value::None));
+        assert_eq!(dict.len(), 2);
+
+        assert_eq!(dict.get(&Value::Bool(true)), Some(&Value::Int(3)));
+        assert_eq!(dict.get(&Value::Unicode("a".into())), Some(&Value::Int(2)));
+        assert_eq!(dict.get(&Value::String(b"a"[..].into())), None);
+        assert_eq!(dict.get(&Value::Int(2)), None);
+
+        // The original key and position are kept
+        match dict.iter().next() {
+            Some(&(Value::Int(1), Value::Int(3))) => (),
+            _ => assert!(false),
+        }
+
+        let key = Value::Tuple(Shared::new(vec![Value::Int(1), Value::None]));
+        dict.insert(key, Value::Int(4));
+        let key = Value::Tuple(Shared::new(vec![Value::Float(1.0), Value::None]));
+        assert_eq!(dict.get(&key), Some(&Value::Int(4)));
+    }
 }